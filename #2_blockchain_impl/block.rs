@@ -2,9 +2,22 @@ use chrono::Utc;
 use log::info;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use crate::DIFFICULTY_PREFIX;
+use std::time::{Duration, Instant};
+use crate::key::hash_string;
+use crate::logging;
+use crate::merkle::{leaf_hash, verify_proof, MerkleTree};
 use crate::transaction::Transaction;
 
+/* Mining logs its progress every this many nonces */
+pub const MINING_LOG_INTERVAL: u64 = 100000;
+/* Leading zero bits a block hash needs unless `--difficulty` says otherwise. The
+   old "00" prefix check ran on unpadded per-byte binary, which only matches when
+   the first two bytes are zero, i.e. 16 bits. */
+pub const DEFAULT_DIFFICULTY_BITS: u32 = 16;
+/* Most transactions a block may hold, coinbase included */
+pub const MAX_TRANSACTIONS_PER_BLOCK: usize = 100;
+/* Default length of a `hashrate` measurement */
+pub const HASHRATE_SAMPLE_SECS: u64 = 5;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
@@ -13,55 +26,91 @@ pub struct Block {
     pub previous_hash: String,
     pub timestamp: i64,
     pub data: String,
+    /* commits the header (and so the hash) to `transactions` */
+    pub merkle_root: String,
     pub transactions: Vec<Transaction>,
     pub nonce: u64,
 }
 
 impl Block {
-    pub fn new(id: u64, previous_hash: String, data: String, transactions: Vec<Transaction>) -> Self {
-        let now = Utc::now();
-        let (nonce, hash) = mine_block(id, now.timestamp(), &previous_hash, &data);
+    pub fn new(
+        id: u64,
+        previous_hash: String,
+        previous_timestamp: i64,
+        data: String,
+        mut transactions: Vec<Transaction>,
+        difficulty: u32,
+    ) -> Self {
+        canonical_order(&mut transactions);
+        let timestamp = next_timestamp(previous_timestamp);
+        let merkle_root = merkle_root(&transactions);
+        let (nonce, hash) = mine_block(id, timestamp, &previous_hash, &data, &merkle_root, difficulty);
         Self {
             id,
             hash,
-            timestamp: now.timestamp(),
+            timestamp,
             previous_hash,
             data,
+            merkle_root,
             nonce,
             transactions,
         }
     }
+
+    /* Light-client check that `tx` is in this block using only the header: the
+       header must hash to `hash` (so `merkle_root` is the one that was mined) and
+       `proof`, as made by merkle_proof, must lead from the transaction to that root.
+       `transactions` is not read, so a header with an empty body is enough. */
+    #[allow(dead_code)]
+    pub fn verify_transaction_inclusion(&self, tx: &Transaction, proof: &[(String, bool)]) -> bool {
+        let header_hash = calculate_hash(self.id, self.timestamp, &self.previous_hash, &self.data, &self.merkle_root, self.nonce);
+        if hex::encode(header_hash) != self.hash {
+            return false;
+        }
+        verify_proof(&leaf_hash(&tx.merkle_leaf_data()), proof, &self.merkle_root)
+    }
 }
 
-pub fn calculate_hash(id: u64, timestamp: i64, previous_hash: &str, data: &str, nonce: u64) -> Vec<u8> {
+/* Block times must be strictly increasing: if the clock hasn't moved past the
+   parent's second yet, bump to parent + 1 instead of reusing its timestamp. */
+pub fn next_timestamp(previous_timestamp: i64) -> i64 {
+    Utc::now().timestamp().max(previous_timestamp + 1)
+}
+
+/* Transactions enter the hash through `merkle_root`; validation checks that the
+   root matches the block's transactions */
+pub fn calculate_hash(id: u64, timestamp: i64, previous_hash: &str, data: &str, merkle_root: &str, nonce: u64) -> Vec<u8> {
     let data = serde_json::json!({
         "id": id,
         "previous_hash": previous_hash,
         "data": data,
+        "merkle_root": merkle_root,
         "timestamp": timestamp,
         "nonce": nonce
     });
     let mut hasher = Sha256::new();
     hasher.update(data.to_string().as_bytes());
-    hasher.finalize().as_slice().to_owned()
+    hasher.finalize().to_vec()
 }
 
-pub fn mine_block(id: u64, timestamp: i64, previous_hash: &str, data: &str) -> (u64, String) {
-    info!("mining block...");
+/* Proof of work: try nonces from 0 until the block hash has `difficulty` leading
+   zero bits. Genesis is mined through here too, which makes it deterministic. */
+pub fn mine_block(id: u64, timestamp: i64, previous_hash: &str, data: &str, merkle_root: &str, difficulty: u32) -> (u64, String) {
+    info!(target: logging::MINER, "mining block...");
     let mut nonce = 0;
 
     loop {
-        if nonce % 100000 == 0 {
-            info!("nonce: {}", nonce);
+        if nonce % MINING_LOG_INTERVAL == 0 {
+            info!(target: logging::MINER, "nonce: {}", nonce);
         }
-        let hash = calculate_hash(id, timestamp, previous_hash, data, nonce);
-        let binary_hash = hash_to_binary_representation(&hash);
-        if binary_hash.starts_with(DIFFICULTY_PREFIX) {
+        let hash = calculate_hash(id, timestamp, previous_hash, data, merkle_root, nonce);
+        if meets_difficulty(&hash, difficulty) {
             info!(
+                target: logging::MINER,
                 "mined! nonce: {}, hash: {}, binary hash: {}",
                 nonce,
                 hex::encode(&hash),
-                binary_hash
+                hash_to_binary_representation(&hash)
             );
             return (nonce, hex::encode(hash));
         }
@@ -69,6 +118,79 @@ pub fn mine_block(id: u64, timestamp: i64, previous_hash: &str, data: &str) -> (
     }
 }
 
+pub fn meets_difficulty(hash: &[u8], difficulty: u32) -> bool {
+    leading_zero_bits(hash) >= difficulty
+}
+
+/* Runs the same hash-and-check step as mine_block on a throwaway block for
+   `sample`, without stopping at a solution. Returns (attempts, elapsed). */
+pub fn measure_hashrate(sample: Duration) -> (u64, Duration) {
+    let timestamp = Utc::now().timestamp();
+    let start = Instant::now();
+    let mut attempts = 0;
+    while start.elapsed() < sample {
+        let hash = calculate_hash(0, timestamp, "hashrate", "hashrate", "hashrate", attempts);
+        let _ = meets_difficulty(&hash, DEFAULT_DIFFICULTY_BITS);
+        attempts += 1;
+    }
+    (attempts, start.elapsed())
+}
+
+pub fn hashes_per_second(attempts: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return 0.0;
+    }
+    attempts as f64 / secs
+}
+
+/* Transactions in a block are the coinbase first, then the rest sorted by txid,
+   so the same selection always gives the same merkle root no matter what order
+   the mempool handed them out in */
+pub fn canonical_order(transactions: &mut [Transaction]) {
+    transactions.sort_by_cached_key(|tx| (!tx.is_coinbase(), tx.id()));
+}
+
+pub fn is_canonical_order(transactions: &[Transaction]) -> bool {
+    transactions
+        .windows(2)
+        .all(|pair| (!pair[0].is_coinbase(), pair[0].id()) <= (!pair[1].is_coinbase(), pair[1].id()))
+}
+
+/* Root of the merkle tree over each transaction's merkle_leaf_data (signature
+   included); no transactions gives the hash of empty input */
+pub fn merkle_root(transactions: &[Transaction]) -> String {
+    let leaves: Vec<String> = transactions.iter().map(Transaction::merkle_leaf_data).collect();
+    MerkleTree::new(&leaves).root_hash().unwrap_or_else(|| hash_string(""))
+}
+
+/* Inclusion proof for transaction `tx_id` against merkle_root(transactions): its
+   leaf hash and the sibling hashes up to the root (true = sibling on the left) */
+pub fn merkle_proof(transactions: &[Transaction], tx_id: &str) -> Option<(String, Vec<(String, bool)>)> {
+    let tx = transactions.iter().find(|tx| tx.id() == tx_id)?;
+    let leaves: Vec<String> = transactions.iter().map(Transaction::merkle_leaf_data).collect();
+    let leaf = leaf_hash(&tx.merkle_leaf_data());
+    let proof = MerkleTree::new(&leaves).generate_proof(&leaf)?;
+    Some((leaf, proof))
+}
+
+/* Work a block represents: the leading zero bits of its hash (0 if the hash isn't hex) */
+pub fn block_work(block: &Block) -> u128 {
+    hex::decode(&block.hash).map_or(0, |hash| leading_zero_bits(&hash) as u128)
+}
+
+/* Number of zero bits at the start of a hash */
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
 pub fn hash_to_binary_representation(hash: &[u8]) -> String {
     let mut res: String = String::default();
     for c in hash {
@@ -76,3 +198,91 @@ pub fn hash_to_binary_representation(hash: &[u8]) -> String {
     }
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::KeyMaster;
+
+    fn transfers(count: usize) -> Vec<Transaction> {
+        let key = KeyMaster::new();
+        let mut transactions = vec![Transaction::coinbase(key.public_key.clone(), 10.0, 1)];
+        for nonce in 0..count as u64 {
+            let mut tx = Transaction::new(key.public_key.clone(), KeyMaster::new().public_key, 1.0, 0.1);
+            tx.nonce = nonce;
+            tx.sign(&key).unwrap();
+            transactions.push(tx);
+        }
+        transactions
+    }
+
+    #[test]
+    fn timestamps_strictly_increase() {
+        let now = Utc::now().timestamp();
+        assert!(next_timestamp(now - 100) >= now);
+        // a parent at or ahead of the clock still gets a later child
+        assert_eq!(next_timestamp(now + 100), now + 101);
+
+        let mut previous = now;
+        for _ in 0..5 {
+            let timestamp = next_timestamp(previous);
+            assert!(timestamp > previous);
+            previous = timestamp;
+        }
+    }
+
+    #[test]
+    fn merkle_root_ignores_float_formatting() {
+        let tx = Transaction::new("alice".to_string(), "bob".to_string(), 1.5, 0.1);
+        let json = serde_json::to_string(&tx).unwrap();
+        // the same values as another serializer might print them
+        let reformatted = json.replace("1.5", "1.50000").replace("0.1", "1e-1");
+        assert_ne!(json, reformatted);
+
+        let received: Transaction = serde_json::from_str(&reformatted).unwrap();
+        assert_eq!(merkle_root(&[tx]), merkle_root(&[received]));
+    }
+
+    #[test]
+    fn hashes_per_second_divides_by_elapsed() {
+        assert_eq!(hashes_per_second(100, Duration::from_secs(2)), 50.0);
+        assert_eq!(hashes_per_second(3, Duration::from_millis(500)), 6.0);
+        assert_eq!(hashes_per_second(100, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn transaction_order_does_not_change_the_block() {
+        // a parent ahead of the clock pins the timestamp, see next_timestamp
+        let previous_timestamp = Utc::now().timestamp() + 1000;
+        let transactions = transfers(3);
+        let mut shuffled = transactions.clone();
+        shuffled.reverse();
+
+        let first = Block::new(1, "00".repeat(32), previous_timestamp, "data".to_string(), transactions, 0);
+        let second = Block::new(1, "00".repeat(32), previous_timestamp, "data".to_string(), shuffled, 0);
+        assert_eq!(first.hash, second.hash);
+        assert!(first.transactions[0].is_coinbase());
+        assert!(is_canonical_order(&first.transactions));
+    }
+
+    #[test]
+    fn inclusion_proof_checks_against_header_alone() {
+        let block = Block::new(1, "00".repeat(32), 0, "data".to_string(), transfers(4), 0);
+        let tx = block.transactions[2].clone();
+        let (_, proof) = merkle_proof(&block.transactions, &tx.id()).unwrap();
+
+        let mut header = block.clone();
+        header.transactions.clear();
+        assert!(header.verify_transaction_inclusion(&tx, &proof));
+
+        let mut tampered = tx.clone();
+        tampered.amount += 1.0;
+        assert!(!header.verify_transaction_inclusion(&tampered, &proof));
+
+        // a root swapped in after mining no longer matches the hash
+        let mut forged = header.clone();
+        forged.merkle_root = merkle_root(&[tampered.clone()]);
+        let (_, forged_proof) = merkle_proof(&[tampered.clone()], &tampered.id()).unwrap();
+        assert!(!forged.verify_transaction_inclusion(&tampered, &forged_proof));
+    }
+}
@@ -1,96 +1,2207 @@
 use chrono::Utc;
-use log::{error, warn};
-use crate::block::{Block, calculate_hash, hash_to_binary_representation};
-use crate::DIFFICULTY_PREFIX;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{error, info, warn};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use crate::block::{
+    block_work, mine_block, Block, calculate_hash, is_canonical_order, merkle_root, DEFAULT_DIFFICULTY_BITS, MAX_TRANSACTIONS_PER_BLOCK,
+};
+use crate::config::GenesisConfig;
+use crate::consensus::{Consensus, ProofOfWork};
+use crate::logging;
+use crate::warn_limited;
+use crate::key::{address_from_public_key, hash_string};
+use crate::mempool::Mempool;
+use crate::wal::Wal;
+use crate::transaction::{is_valid_address, Transaction, TxRejectReason, BURN_ADDRESS, MAX_TX_FUTURE_DRIFT_SECS};
+
+pub const GENESIS_PREVIOUS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+pub const GENESIS_DATA: &str = "Genesis";
+/* Number of recent blocks the throughput estimate looks at */
+pub const TPS_WINDOW_BLOCKS: usize = 10;
+/* Float slack when comparing balances: one unit in the last of AMOUNT_DECIMALS */
+pub const BALANCE_EPSILON: f64 = 1e-8;
+/* How far ahead of the local clock a block's timestamp may be */
+pub const MAX_BLOCK_FUTURE_DRIFT_SECS: i64 = 60;
+/* A retarget moves difficulty by at most this many bits (4x) either way */
+pub const MAX_RETARGET_STEP_BITS: i64 = 2;
+/* Entries the block validation cache holds before it is emptied */
+pub const VALIDATION_CACHE_CAPACITY: usize = 1024;
+/* Reorgs a slow subscriber may fall behind by before it starts missing them */
+pub const REORG_CHANNEL_CAPACITY: usize = 16;
+/* Blocks held back waiting for their parent; past it the oldest makes way. Also
+   how far past the tip a held block may be. */
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+/* Version of the chain file layout and of the hashing it was written under.
+   Bumped when stored blocks stop validating: 2 came with domain-separated
+   merkle hashes, so files from before (a bare block array) are refused by
+   name instead of failing validation. */
+pub const CHAIN_FORMAT_VERSION: u32 = 2;
 
 pub struct Blockchain {
-    pub mining_reward: f32,
+    pub mining_reward: f64,
     pub blocks: Vec<Block>,
+    pub mempool: Mempool,
+    pub consensus: Box<dyn Consensus + Send>,
+    pub genesis_config: GenesisConfig,
+    reorg_sender: broadcast::Sender<ReorgEvent>,
+    /* when set, blocks are logged here before they are accepted */
+    pub wal: Option<Wal>,
+    /* when set, the chain is rewritten here whenever it changes */
+    pub chain_file: Option<PathBuf>,
+    /* timestamped copies of chain_file kept after each save; 0 keeps none */
+    pub backups: usize,
+    /* leading zero bits a block hash needs; the starting point when genesis_config.retarget is set */
+    pub difficulty: u32,
+    /* (digest of the whole block, parent hash) of blocks is_block_valid_on accepted.
+       The parent hash pins the history, the digest the contents: the claimed
+       block hash alone could be reused by a tampered block. Only passes are
+       kept since some failures (future timestamps) go away with time. */
+    validated: Mutex<HashSet<(String, String)>>,
+    validation_cache_hits: AtomicU64,
+    /* blocks that arrived ahead of their parent, by previous_hash; attached once the parent is */
+    orphans: HashMap<String, Vec<Block>>,
+    /* (previous_hash, hash) of the held blocks, oldest first */
+    orphan_order: VecDeque<(String, String)>,
+}
+
+/* What save_to_file writes */
+#[derive(Serialize, Deserialize)]
+struct StoredChain<'a> {
+    version: u32,
+    blocks: Cow<'a, [Block]>,
+}
+
+/* Sent when choose_chain drops local blocks in favour of a remote chain */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReorgEvent {
+    /* height of the first block that differs between the old and new chain */
+    pub fork_height: u64,
+    /* hashes of the local blocks from fork_height up that are no longer on the chain */
+    pub orphaned_hashes: Vec<String>,
+    pub new_tip: String,
+}
+
+/* Why choose_chain kept neither chain */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainSelectionError {
+    BothInvalid,
+}
+
+impl std::fmt::Display for ChainSelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainSelectionError::BothInvalid => write!(f, "local and remote chains are both invalid"),
+        }
+    }
 }
 
+/* Why a block was refused as the next block of a chain */
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockValidationError {
+    NotNextId { id: u64, parent_id: u64 },
+    PreviousHashMismatch,
+    TimestampNotIncreasing,
+    FutureTimestamp,
+    InsufficientWork,
+    TooManyTransactions(usize),
+    NonCanonicalOrder,
+    DuplicateTransaction,
+    MerkleRootMismatch,
+    HashMismatch,
+    BadSignature,
+    InvalidTransaction(TxRejectReason),
+    InvalidCoinbase,
+    SpendLimitExceeded,
+    Overspend,
+    NonceOutOfSequence,
+    /* the parent is unknown and the block is more than MAX_ORPHAN_BLOCKS past the tip */
+    TooFarAhead,
+    /* valid, but it couldn't be written to the WAL */
+    Storage(String),
+}
 
+impl std::fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockValidationError::NotNextId { id, parent_id } => write!(f, "id#{} does not follow its parent id#{}", id, parent_id),
+            BlockValidationError::PreviousHashMismatch => write!(f, "previous hash is not the parent's hash"),
+            BlockValidationError::TimestampNotIncreasing => write!(f, "timestamp is not after its parent's"),
+            BlockValidationError::FutureTimestamp => write!(f, "timestamp is too far in the future"),
+            BlockValidationError::InsufficientWork => write!(f, "hash does not meet the difficulty"),
+            BlockValidationError::TooManyTransactions(count) => {
+                write!(f, "{} transactions, the limit is {}", count, MAX_TRANSACTIONS_PER_BLOCK)
+            }
+            BlockValidationError::NonCanonicalOrder => write!(f, "transactions are out of canonical order"),
+            BlockValidationError::DuplicateTransaction => write!(f, "contains the same transaction twice"),
+            BlockValidationError::MerkleRootMismatch => write!(f, "merkle root doesn't match its transactions"),
+            BlockValidationError::HashMismatch => write!(f, "hash doesn't match its header"),
+            BlockValidationError::BadSignature => write!(f, "a transaction has a bad signature"),
+            BlockValidationError::InvalidTransaction(reason) => write!(f, "invalid transaction: {:?}", reason),
+            BlockValidationError::InvalidCoinbase => write!(f, "invalid coinbase"),
+            BlockValidationError::SpendLimitExceeded => write!(f, "a sender exceeds the spend limit"),
+            BlockValidationError::Overspend => write!(f, "a sender spends more than it holds"),
+            BlockValidationError::NonceOutOfSequence => write!(f, "nonces out of sequence"),
+            BlockValidationError::TooFarAhead => write!(f, "parent unknown and too far past the tip to hold"),
+            BlockValidationError::Storage(e) => write!(f, "can't write it to the WAL: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlockValidationError {}
+
+/* Summary numbers for `stats` */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainStats {
+    /* see Blockchain::chain_id; None before genesis */
+    pub chain_id: Option<String>,
+    pub height: usize,
+    /* every confirmed transaction, coinbases included */
+    pub total_transactions: usize,
+    pub mempool_size: usize,
+    /* leading zero bits the next block needs */
+    pub difficulty: u32,
+    pub transactions_per_second: Option<f64>,
+    pub estimated_hashrate: Option<f64>,
+    pub issued: f64,
+    pub burned: f64,
+    pub circulating_supply: f64,
+    /* block validations answered from the cache since startup */
+    pub validation_cache_hits: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainDiff {
+    /* height of the last block both chains share; None if even genesis differs */
+    pub common_ancestor: Option<u64>,
+    pub local_only: Vec<String>,
+    pub remote_only: Vec<String>,
+}
 
 impl Blockchain {
     pub fn new() -> Self {
-        Self { mining_reward: 10.0, blocks: vec![] }
+        Self::with_consensus(Box::new(ProofOfWork))
+    }
+
+    pub fn with_consensus(consensus: Box<dyn Consensus + Send>) -> Self {
+        Self {
+            mining_reward: 10.0,
+            blocks: vec![],
+            mempool: Mempool::new(),
+            consensus,
+            genesis_config: GenesisConfig::default(),
+            reorg_sender: broadcast::channel(REORG_CHANNEL_CAPACITY).0,
+            wal: None,
+            chain_file: None,
+            backups: 0,
+            difficulty: DEFAULT_DIFFICULTY_BITS,
+            validated: Mutex::new(HashSet::new()),
+            validation_cache_hits: AtomicU64::new(0),
+            orphans: HashMap::new(),
+            orphan_order: VecDeque::new(),
+        }
     }
 
+    /* Consumers that derive state from blocks (indexers, wallets) roll it back on these */
+    pub fn subscribe_reorgs(&self) -> broadcast::Receiver<ReorgEvent> {
+        self.reorg_sender.subscribe()
+    }
+
+    /* Every input is fixed (timestamp from the genesis config, GENESIS_DATA, no
+       transactions) and the nonce search starts from 0, so every node with the
+       same config and difficulty mines the identical block */
     pub(crate) fn genesis(&mut self) {
+        let timestamp = self.genesis_config.genesis_timestamp;
+        let root = merkle_root(&[]);
+        let (nonce, hash) = mine_block(0, timestamp, GENESIS_PREVIOUS_HASH, GENESIS_DATA, &root, self.expected_difficulty(0));
         let genesis_block = Block {
             id: 0,
-            timestamp: Utc::now().timestamp(),
-            previous_hash: String::from("0000000000000000000000000000000000000000000000000000000000000000"),
-            nonce: 0,
-            hash: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
-            data: "Genesis".to_string(),
+            timestamp,
+            previous_hash: GENESIS_PREVIOUS_HASH.to_string(),
+            nonce,
+            hash,
+            data: GENESIS_DATA.to_string(),
+            merkle_root: root,
             transactions: vec![],
         };
         self.blocks.push(genesis_block);
     }
 
-    pub fn try_add_block(&mut self,block: Block) {
-        let latest_block = self.blocks.last().expect("there is at least one block.");
-        if self.is_block_valid(&block, latest_block) {
-            self.blocks.push(block);
-        }else {
-            error!("could not add block - invalid");
+    /* Block 0 has no parent to check against, so pin down its own fields instead */
+    pub fn is_genesis_valid(&self, genesis: &Block) -> bool {
+        if genesis.id != 0 {
+            warn!(target: logging::CONSENSUS, "genesis block has id#{}", genesis.id);
+            return false;
         }
-    }
-
-    pub fn is_block_valid(&self, block: &Block, previous_block: &Block) -> bool {
-        if block.previous_hash != previous_block.hash {
-            warn!("block with id#{} has wrong previous hash",block.id);
+        if genesis.previous_hash != GENESIS_PREVIOUS_HASH {
+            warn!(target: logging::CONSENSUS, "genesis block has non-zero previous hash {}", genesis.previous_hash);
             return false;
-        }else if !hash_to_binary_representation(
-            &hex::decode(&block.hash).expect("can decode from hex"),
-        ).starts_with(DIFFICULTY_PREFIX){
-            warn!(
-                "block with id#{} is not the next block after the latest: {}",
-                block.id, previous_block.id
-            );
+        }
+        if genesis.merkle_root != merkle_root(&genesis.transactions) {
+            warn!(target: logging::CONSENSUS, "genesis block has wrong merkle root");
+            return false;
+        }
+        let hash = calculate_hash(
+            genesis.id,
+            genesis.timestamp,
+            &genesis.previous_hash,
+            &genesis.data,
+            &genesis.merkle_root,
+            genesis.nonce,
+        );
+        if hex::encode(&hash) != genesis.hash {
+            warn!(target: logging::CONSENSUS, "genesis block has invalid hash");
             return false;
-        }else if hex::encode(calculate_hash(
+        }
+        if !self.consensus.validate_block(genesis, &hash, self.expected_difficulty_on(&[], 0)) {
+            return false;
+        }
+        true
+    }
+
+    /* Short network identifier derived from the genesis hash; two nodes
+       with the same chain_id agree on block 0. None until genesis exists. */
+    pub fn chain_id(&self) -> Option<String> {
+        Blockchain::chain_id_on(&self.blocks)
+    }
+
+    fn chain_id_on(history: &[Block]) -> Option<String> {
+        history
+            .first()
+            .map(|genesis| hash_string(&genesis.hash)[..16].to_string())
+    }
+
+    /* One row per transaction; blocks without transactions contribute no rows */
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("block_id,block_timestamp,txid,sender,receiver,amount,fee\n");
+        for block in &self.blocks {
+            for tx in &block.transactions {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{}\n",
+                    block.id, block.timestamp, tx.id(), tx.sender, tx.receiver, tx.amount, tx.fee
+                ));
+            }
+        }
+        csv
+    }
+
+    /* Blocks as JSON, tagged with CHAIN_FORMAT_VERSION; gzip-compressed when the
+       path ends in `.gz`. Written to a temporary file first and renamed over
+       `path`, so a crash never leaves a half-written chain behind. */
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec(&StoredChain { version: CHAIN_FORMAT_VERSION, blocks: Cow::Borrowed(&self.blocks) })?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            let mut encoder = GzEncoder::new(&mut file, Compression::default());
+            encoder.write_all(&json)?;
+            encoder.finish()?;
+        } else {
+            file.write_all(&json)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /* Replaces our blocks with the ones stored at `path`, validated under this
+       chain's own rules (genesis config included). Accepts plain or gzip JSON
+       regardless of extension (sniffs the gzip magic bytes). */
+    pub fn load_blocks(&mut self, path: &Path) -> io::Result<()> {
+        let raw = fs::read(path)?;
+        let json = if raw.starts_with(&[0x1f, 0x8b]) {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&raw[..]).read_to_end(&mut decoded)?;
+            decoded
+        } else {
+            raw
+        };
+        // a bare array is the unversioned layout from before CHAIN_FORMAT_VERSION 2
+        let stored: StoredChain = match serde_json::from_slice::<serde_json::Value>(&json)? {
+            serde_json::Value::Array(_) => StoredChain { version: 1, blocks: Cow::Owned(vec![]) },
+            value => serde_json::from_value(value)?,
+        };
+        if stored.version != CHAIN_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "stored chain has format version {}, this node reads version {}; resync it from peers",
+                    stored.version, CHAIN_FORMAT_VERSION
+                ),
+            ));
+        }
+        let blocks = stored.blocks.into_owned();
+        if !self.is_chain_valid(&blocks) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stored chain is invalid"));
+        }
+        self.blocks = blocks;
+        Ok(())
+    }
+
+    /* Re-admits the transactions a previous run saved at `path`, skipping those the
+       chain has confirmed since or that no longer validate on it; returns how many
+       went back into the mempool */
+    pub fn restore_mempool(&mut self, path: &Path) -> io::Result<usize> {
+        let mut restored = 0;
+        for tx in Mempool::load_from_file(path)? {
+            let id = tx.id();
+            if self.find_transaction(&id).is_some() {
+                continue;
+            }
+            if let Err(reason) = self.validate_transaction(&tx) {
+                info!(target: logging::MEMPOOL, "not restoring transaction {}: {:?}", id, reason);
+                continue;
+            }
+            if self.mempool.add_transaction(tx) {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /* Backups sit next to the chain file as `<chain file>.<unix millis>.bak`;
+       newest first */
+    pub fn list_backups(chain_file: &Path) -> io::Result<Vec<PathBuf>> {
+        let dir = match chain_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = match chain_file.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(vec![]),
+        };
+        let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy())
+                    .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".bak"))
+            })
+            .collect();
+        // zero-padded millis, so name order is age order
+        backups.sort();
+        backups.reverse();
+        Ok(backups)
+    }
+
+    fn rotate_backups(&self, chain_file: &Path) -> io::Result<()> {
+        let mut backup = chain_file.as_os_str().to_owned();
+        backup.push(format!(".{:013}.bak", Utc::now().timestamp_millis()));
+        fs::copy(chain_file, PathBuf::from(backup))?;
+        for old in Blockchain::list_backups(chain_file)?.into_iter().skip(self.backups) {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    /* Replaces the chain with backup `n` (1 = newest) and saves it as the current chain */
+    pub fn restore_backup(&mut self, n: usize) -> io::Result<PathBuf> {
+        let chain_file = self
+            .chain_file
+            .clone()
+            .ok_or_else(|| io::Error::other("the node keeps no chain file"))?;
+        let backup = n
+            .checked_sub(1)
+            .and_then(|index| Blockchain::list_backups(&chain_file).ok()?.into_iter().nth(index))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no backup #{}", n)))?;
+        self.load_blocks(&backup)?;
+        self.persist();
+        Ok(backup)
+    }
+
+    /* Rewrites chain_file (if set); once that worked, the WAL's blocks are safe there */
+    pub fn persist(&mut self) {
+        let path = match &self.chain_file {
+            Some(path) => path,
+            None => return,
+        };
+        if let Err(e) = self.save_to_file(path) {
+            error!(target: logging::CONSENSUS, "can't save the chain to {}: {}", path.display(), e);
+            return;
+        }
+        if self.backups > 0 {
+            if let Err(e) = self.rotate_backups(path) {
+                error!(target: logging::CONSENSUS, "can't back up {}: {}", path.display(), e);
+            }
+        }
+        if let Some(wal) = &mut self.wal {
+            if let Err(e) = wal.clear() {
+                error!(target: logging::CONSENSUS, "can't clear the WAL: {}", e);
+            }
+        }
+    }
+
+    /* Coins created so far: the coinbase reward of every mined block. Coinbases
+       also pass on the miner's share of fees, which were already in circulation,
+       so that part isn't counted. */
+    pub fn total_issued(&self) -> f64 {
+        self.blocks
+            .iter()
+            .map(|block| {
+                let coinbase: f64 = block.transactions.iter().filter(|tx| tx.is_coinbase()).map(|tx| tx.amount).sum();
+                coinbase - self.miner_fee_share(block)
+            })
+            .sum()
+    }
+
+    /* Coinbase amount a block at `height` must pay its miner */
+    pub fn block_reward(&self, _height: u64) -> f64 {
+        self.mining_reward
+    }
+
+    /* Coins sent to BURN_ADDRESS plus the burned share of fees, in confirmed blocks */
+    pub fn total_burned(&self) -> f64 {
+        let sent_to_burn: f64 = self.blocks
+            .iter()
+            .flat_map(|b| b.transactions.iter())
+            .filter(|tx| tx.is_burn())
+            .map(|tx| tx.amount)
+            .sum();
+        let fees: f64 = self.blocks
+            .iter()
+            .flat_map(|b| b.transactions.iter())
+            .map(|tx| tx.fee)
+            .sum();
+        sent_to_burn + fees * self.genesis_config.burn_fraction
+    }
+
+    /* Credits minus debits for `address` over every confirmed transaction; the
+       sender pays amount + fee. Block rewards and the miner's share of fees
+       arrive as coinbase credits. */
+    pub fn balance_of(&self, address: &str) -> f64 {
+        Blockchain::balance_on(address, &self.blocks)
+    }
+
+    /* The public key behind a short address, if it ever appeared on the chain */
+    pub fn public_key_for_address(&self, address: &str) -> Option<String> {
+        self.blocks
+            .iter()
+            .flat_map(|b| b.transactions.iter())
+            .flat_map(|tx| [&tx.sender, &tx.receiver])
+            .find(|key| address_from_public_key(key) == address)
+            .cloned()
+    }
+
+    /* One past the highest nonce `sender` has used on the chain, 0 if it never sent */
+    pub fn next_nonce_on(sender: &str, history: &[Block]) -> u64 {
+        history
+            .iter()
+            .flat_map(|b| b.transactions.iter())
+            .filter(|tx| tx.sender == sender && !tx.is_coinbase())
+            .map(|tx| tx.nonce + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /* Nonce for a new transaction from `sender`: after the chain and its pending ones */
+    pub fn next_nonce(&self, sender: &str) -> u64 {
+        let pending = self
+            .mempool
+            .transactions()
+            .iter()
+            .filter(|tx| tx.sender == sender)
+            .map(|tx| tx.nonce + 1)
+            .max()
+            .unwrap_or(0);
+        Blockchain::next_nonce_on(sender, &self.blocks).max(pending)
+    }
+
+    /* Pending transactions for the next block on the local chain, taken in
+       select_for_block order up to MAX_TRANSACTIONS_PER_BLOCK (less one for the
+       coinbase). A transaction is skipped while it wouldn't validate, isn't its
+       sender's next nonce, or its sender can't cover it from the chain balance
+       after the ones already taken. Incoming amounts aren't counted: the block is
+       checked in txid order, so a credit can't be relied on to land first. */
+    pub fn block_template(&self) -> Vec<Transaction> {
+        let mut candidates = self.mempool.select_for_block(self.mempool.len());
+        let mut next_nonces: HashMap<String, u64> = HashMap::new();
+        let mut balances: HashMap<String, f64> = HashMap::new();
+        let mut selected = vec![];
+        // a later nonce can become includable once its predecessor is taken, so repeat until nothing moves
+        let mut progress = true;
+        while progress && selected.len() < MAX_TRANSACTIONS_PER_BLOCK - 1 {
+            progress = false;
+            let mut remaining = vec![];
+            for tx in candidates {
+                if selected.len() >= MAX_TRANSACTIONS_PER_BLOCK - 1 || self.validate_transaction(&tx).is_err() {
+                    remaining.push(tx);
+                    continue;
+                }
+                let next_nonce = *next_nonces
+                    .entry(tx.sender.clone())
+                    .or_insert_with(|| Blockchain::next_nonce_on(&tx.sender, &self.blocks));
+                let balance = *balances
+                    .entry(tx.sender.clone())
+                    .or_insert_with(|| self.balance_of(&tx.sender));
+                if tx.nonce != next_nonce || balance - tx.amount - tx.fee < -BALANCE_EPSILON {
+                    remaining.push(tx);
+                    continue;
+                }
+                next_nonces.insert(tx.sender.clone(), next_nonce + 1);
+                balances.insert(tx.sender.clone(), balance - tx.amount - tx.fee);
+                selected.push(tx);
+                progress = true;
+            }
+            candidates = remaining;
+        }
+        selected
+    }
+
+    /* balance_of minus what `address` already spends in pending mempool transactions */
+    pub fn available_balance(&self, address: &str) -> f64 {
+        let pending: f64 = self
+            .mempool
+            .transactions()
+            .iter()
+            .filter(|tx| tx.sender == address)
+            .map(|tx| tx.amount + tx.fee)
+            .sum();
+        self.balance_of(address) - pending
+    }
+
+    /* Same, as of the end of `history` */
+    pub fn balance_on(address: &str, history: &[Block]) -> f64 {
+        let mut balance = 0.0;
+        for tx in history.iter().flat_map(|b| b.transactions.iter()) {
+            if tx.receiver == address {
+                balance += tx.amount;
+            }
+            if tx.sender == address {
+                balance -= tx.amount + tx.fee;
+            }
+        }
+        // float error can leave a tiny negative remainder on a spent-out account
+        if balance < 0.0 && balance > -BALANCE_EPSILON {
+            0.0
+        } else {
+            balance
+        }
+    }
+
+    /* Every address holding a positive balance, with that balance. Coins sent to
+       BURN_ADDRESS are gone, so it isn't listed. */
+    pub fn account_balances(&self) -> HashMap<String, f64> {
+        let mut balances: HashMap<String, f64> = HashMap::new();
+        for tx in self.blocks.iter().flat_map(|b| b.transactions.iter()) {
+            *balances.entry(tx.receiver.clone()).or_insert(0.0) += tx.amount;
+            *balances.entry(tx.sender.clone()).or_insert(0.0) -= tx.amount + tx.fee;
+        }
+        balances.remove(BURN_ADDRESS);
+        balances.retain(|_, balance| *balance > BALANCE_EPSILON);
+        balances
+    }
+
+    /* What the producer of `block` earns from its fees once the burn share is taken out */
+    pub fn miner_fee_share(&self, block: &Block) -> f64 {
+        self.fee_share(&block.transactions)
+    }
+
+    pub fn fee_share(&self, transactions: &[Transaction]) -> f64 {
+        let fees: f64 = transactions.iter().map(|tx| tx.fee).sum();
+        fees * (1.0 - self.genesis_config.burn_fraction)
+    }
+
+    /* Coinbase amount for a block at `height` holding `transactions`: the block
+       reward plus the miner's share of their fees */
+    pub fn coinbase_amount(&self, height: u64, transactions: &[Transaction]) -> f64 {
+        self.block_reward(height) + self.fee_share(transactions)
+    }
+
+    pub fn circulating_supply(&self) -> f64 {
+        self.total_issued() - self.total_burned()
+    }
+
+    /* Confirmed transactions per second over the last `window` mined blocks.
+       None until there are two mined blocks with distinct timestamps. */
+    pub fn transactions_per_second(&self, window: usize) -> Option<f64> {
+        let mined = self.blocks.get(1..)?; // genesis has a fixed, not a real, timestamp
+        let recent = &mined[mined.len().saturating_sub(window)..];
+        if recent.len() < 2 {
+            return None;
+        }
+        let elapsed = recent.last()?.timestamp - recent.first()?.timestamp;
+        if elapsed <= 0 {
+            return None;
+        }
+        // the first block's transactions were confirmed when the window opened
+        let confirmed: usize = recent[1..].iter().map(|b| b.transactions.len()).sum();
+        Some(confirmed as f64 / elapsed as f64)
+    }
+
+    /* Hashes per second that mined the last `window` blocks: a block's nonce counts
+       the attempts its miner made, spread over the time since the block before.
+       None until there are two mined blocks with distinct timestamps. */
+    pub fn estimated_hashrate(&self, window: usize) -> Option<f64> {
+        let mined = self.blocks.get(1..)?;
+        let recent = &mined[mined.len().saturating_sub(window)..];
+        if recent.len() < 2 {
+            return None;
+        }
+        let elapsed = recent.last()?.timestamp - recent.first()?.timestamp;
+        if elapsed <= 0 {
+            return None;
+        }
+        let attempts: u64 = recent[1..].iter().map(|b| b.nonce.saturating_add(1)).sum();
+        Some(attempts as f64 / elapsed as f64)
+    }
+
+    /* The tip has 1 confirmation, its parent 2, and so on; 0 for unknown ids */
+    pub fn confirmations(&self, block_id: u64) -> u64 {
+        (self.blocks.len() as u64).saturating_sub(block_id)
+    }
+
+    pub fn is_final(&self, block_id: u64, confirmations: u64) -> bool {
+        self.confirmations(block_id) >= confirmations.max(1)
+    }
+
+    /* Highest block that is final at the given depth */
+    pub fn safe_tip(&self, confirmations: u64) -> Option<&Block> {
+        let depth = confirmations.max(1) as usize;
+        self.blocks.len().checked_sub(depth).and_then(|index| self.blocks.get(index))
+    }
+
+    /* Total work of `chain`, summed per block like ProofOfWork's chain selection */
+    pub fn chain_work(chain: &[Block]) -> u128 {
+        chain.iter().map(block_work).sum()
+    }
+
+    pub fn find_block(&self, hash: &str) -> Option<&Block> {
+        self.blocks.iter().find(|block| block.hash == hash)
+    }
+
+    /* Height of the block containing the transaction, and the transaction itself */
+    pub fn find_transaction(&self, tx_id: &str) -> Option<(u64, Transaction)> {
+        self.blocks.iter().find_map(|block| {
+            block
+                .transactions
+                .iter()
+                .find(|tx| tx.id() == tx_id)
+                .map(|tx| (block.id, tx.clone()))
+        })
+    }
+
+    pub fn stats(&self) -> ChainStats {
+        ChainStats {
+            chain_id: self.chain_id(),
+            height: self.blocks.len(),
+            total_transactions: self.blocks.iter().map(|b| b.transactions.len()).sum(),
+            mempool_size: self.mempool.len(),
+            difficulty: self.expected_difficulty(self.blocks.len() as u64),
+            transactions_per_second: self.transactions_per_second(TPS_WINDOW_BLOCKS),
+            estimated_hashrate: self.estimated_hashrate(TPS_WINDOW_BLOCKS),
+            issued: self.total_issued(),
+            burned: self.total_burned(),
+            circulating_supply: self.circulating_supply(),
+            validation_cache_hits: self.validation_cache_hits(),
+        }
+    }
+
+    /* Validates `block` as the next block and appends it, together with any orphans
+       that connect to it. A block from beyond the tip is held in the orphan pool
+       instead. */
+    pub fn try_add_block(&mut self,block: Block) -> Result<(), BlockValidationError> {
+        if block.id as usize > self.blocks.len() {
+            return self.add_orphan(block);
+        }
+        self.is_block_valid_on(&block, &self.blocks)?;
+        if let Err(e) = self.record_block(block) {
+            error!(target: logging::CONSENSUS, "could not add block - can't write it to the WAL: {}", e);
+            return Err(BlockValidationError::Storage(e.to_string()));
+        }
+        self.connect_orphans();
+        Ok(())
+    }
+
+    /* Number of blocks waiting in the orphan pool */
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.values().map(Vec::len).sum()
+    }
+
+    /* Nothing else can be checked without the parent, but the proof of work can:
+       a held block must hash to its header at the difficulty expected near its
+       height, so filling the pool costs real mining. When the pool is full the
+       block held longest is dropped. */
+    fn add_orphan(&mut self, block: Block) -> Result<(), BlockValidationError> {
+        if block.id as usize > self.blocks.len() + MAX_ORPHAN_BLOCKS {
+            return Err(BlockValidationError::TooFarAhead);
+        }
+        let hash = calculate_hash(
             block.id,
             block.timestamp,
             &block.previous_hash,
             &block.data,
+            &block.merkle_root,
             block.nonce,
-        )) != block.hash
-        {
-            warn!("block with id#{} has invalid hash",block.id);
+        );
+        if hex::encode(&hash) != block.hash {
+            return Err(BlockValidationError::HashMismatch);
+        }
+        if !self.consensus.validate_block(&block, &hash, self.expected_difficulty(block.id)) {
+            return Err(BlockValidationError::InsufficientWork);
+        }
+        let siblings = self.orphans.get(&block.previous_hash);
+        if siblings.is_some_and(|siblings| siblings.iter().any(|b| b.hash == block.hash)) {
+            return Ok(());
+        }
+        while self.orphan_count() >= MAX_ORPHAN_BLOCKS && self.evict_oldest_orphan() {}
+        info!(
+            target: logging::CONSENSUS,
+            "holding block id#{} until its parent {} arrives", block.id, block.previous_hash
+        );
+        self.orphan_order.push_back((block.previous_hash.clone(), block.hash.clone()));
+        self.orphans.entry(block.previous_hash.clone()).or_default().push(block);
+        Ok(())
+    }
+
+    /* false once there is nothing left to evict */
+    fn evict_oldest_orphan(&mut self) -> bool {
+        let Some((previous_hash, hash)) = self.orphan_order.pop_front() else { return false };
+        if let Some(siblings) = self.orphans.get_mut(&previous_hash) {
+            siblings.retain(|b| b.hash != hash);
+            if siblings.is_empty() {
+                self.orphans.remove(&previous_hash);
+            }
+            info!(target: logging::CONSENSUS, "orphan pool full, dropping held block {}", hash);
+        }
+        true
+    }
+
+    /* Attaches orphans to the tip for as long as one of them validates there, then
+       forgets those at heights the chain has already filled */
+    fn connect_orphans(&mut self) {
+        while let Some(tip_hash) = self.blocks.last().map(|tip| tip.hash.clone()) {
+            let Some(children) = self.orphans.remove(&tip_hash) else { break };
+            let mut attached = false;
+            for child in children {
+                if attached {
+                    continue;
+                }
+                if let Err(e) = self.is_block_valid_on(&child, &self.blocks) {
+                    info!(target: logging::CONSENSUS, "dropping orphan block id#{}: {}", child.id, e);
+                    continue;
+                }
+                let id = child.id;
+                match self.record_block(child) {
+                    Ok(()) => {
+                        info!(target: logging::CONSENSUS, "attached orphan block id#{}", id);
+                        attached = true;
+                    }
+                    Err(e) => error!(target: logging::CONSENSUS, "can't write orphan block id#{} to the WAL: {}", id, e),
+                }
+            }
+            if !attached {
+                break;
+            }
+        }
+        let height = self.blocks.len();
+        self.orphans.retain(|_, children| {
+            children.retain(|b| b.id as usize > height);
+            !children.is_empty()
+        });
+        let orphans = &self.orphans;
+        self.orphan_order.retain(|(previous_hash, hash)| {
+            orphans.get(previous_hash).is_some_and(|children| children.iter().any(|b| &b.hash == hash))
+        });
+    }
+
+    /* Appends an already validated (or self-mined) block: logged to the WAL
+       first, so it only becomes part of the chain once it is on disk */
+    pub fn record_block(&mut self, block: Block) -> io::Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.append(std::slice::from_ref(&block))?;
+        }
+        self.mempool.remove_transactions(&block.transactions);
+        self.blocks.push(block);
+        self.drop_stale_transactions();
+        self.persist();
+        Ok(())
+    }
+
+    /* choose_chain between our blocks and `remote`, then apply_reorg to the winner.
+       If neither chain validates the local one is kept as it is. */
+    pub(crate) fn adopt_best_chain(&mut self, remote: Vec<Block>) {
+        match self.choose_chain(self.blocks.clone(), remote) {
+            Ok(chosen) => self.apply_reorg(chosen),
+            Err(e) => error!(target: logging::CONSENSUS, "keeping the local chain: {}", e),
+        }
+    }
+
+    /* Switches to `new_chain`, already validated; the blocks that change go to the WAL.
+       Transactions from local blocks the switch drops go back to the mempool unless
+       the new chain confirms them too or they no longer validate on it. Balances
+       are always computed from the chain, so they follow without further work. */
+    pub fn apply_reorg(&mut self, new_chain: Vec<Block>) {
+        let common = Blockchain::common_prefix_len(&self.blocks, &new_chain);
+        if let Some(wal) = &mut self.wal {
+            if let Err(e) = wal.append(&new_chain[common..]) {
+                error!(target: logging::CONSENSUS, "can't write adopted blocks to the WAL: {}", e);
+            }
+        }
+        let changed = common < new_chain.len() || new_chain.len() < self.blocks.len();
+        let orphaned: Vec<Block> = self.blocks.drain(common..).collect();
+        self.blocks = new_chain;
+        if !changed {
+            return;
+        }
+        let confirmed: HashSet<String> = self.blocks[common.min(self.blocks.len())..]
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(Transaction::id))
+            .collect();
+        let mut returned = 0;
+        // block order keeps each sender's nonces in sequence
+        for tx in orphaned.into_iter().flat_map(|block| block.transactions) {
+            if tx.is_coinbase() || confirmed.contains(&tx.id()) || self.validate_transaction(&tx).is_err() {
+                continue;
+            }
+            if self.mempool.add_transaction(tx) {
+                returned += 1;
+            }
+        }
+        if returned > 0 {
+            info!(target: logging::MEMPOOL, "returned {} transactions from orphaned blocks to the mempool", returned);
+        }
+        self.drop_stale_transactions();
+        self.persist();
+        self.connect_orphans();
+    }
+
+    /* Pending transactions whose nonce the chain has already used can never be mined */
+    fn drop_stale_transactions(&mut self) {
+        let blocks = &self.blocks;
+        let dropped = self
+            .mempool
+            .retain(|tx| tx.nonce >= Blockchain::next_nonce_on(&tx.sender, blocks));
+        if dropped > 0 {
+            info!(target: logging::MEMPOOL, "dropped {} pending transactions with stale nonces", dropped);
+        }
+    }
+
+    /* Re-applies logged blocks on top of the current chain. A logged block
+       replaces whatever sits at its height (the log is newer); blocks that
+       don't connect or don't validate are skipped. Returns how many applied. */
+    pub fn replay_wal(&mut self) -> usize {
+        let logged = match self.wal.as_ref().map(Wal::read_blocks) {
+            Some(Ok(blocks)) => blocks,
+            Some(Err(e)) => {
+                error!(target: logging::CONSENSUS, "can't read the WAL: {}", e);
+                return 0;
+            }
+            None => return 0,
+        };
+        let mut applied = 0;
+        for block in logged {
+            let height = block.id as usize;
+            if height == 0 || height > self.blocks.len() {
+                continue;
+            }
+            if self.blocks.get(height).is_some_and(|b| b.hash == block.hash) {
+                continue;
+            }
+            if let Err(e) = self.is_block_valid_on(&block, &self.blocks[..height]) {
+                warn!(target: logging::CONSENSUS, "skipping logged block id#{}: {}", block.id, e);
+            } else {
+                self.blocks.truncate(height);
+                self.mempool.remove_transactions(&block.transactions);
+                self.blocks.push(block);
+                applied += 1;
+            }
+        }
+        applied
+    }
+
+    /* Every transaction rule, by name, with its individual outcome, against the current chain */
+    pub fn transaction_checks(&self, tx: &Transaction) -> Vec<(&'static str, Result<(), TxRejectReason>)> {
+        self.transaction_checks_on(tx, &self.blocks)
+    }
+
+    /* Same, against the state after `history` (e.g. the chain just before a block);
+       the transaction is judged as part of the block at height history.len() */
+    pub fn transaction_checks_on(&self, tx: &Transaction, history: &[Block]) -> Vec<(&'static str, Result<(), TxRejectReason>)> {
+        let height = history.len() as u64;
+        let address = if is_valid_address(&tx.sender) && (is_valid_address(&tx.receiver) || tx.is_burn()) {
+            Ok(())
+        } else {
+            Err(TxRejectReason::InvalidAddress)
+        };
+        let chain_id = if Blockchain::chain_id_on(history).as_ref() == Some(&tx.chain_id) {
+            Ok(())
+        } else {
+            Err(TxRejectReason::WrongChain)
+        };
+        let signature = if tx.verify() {
+            Ok(())
+        } else {
+            Err(TxRejectReason::InvalidSignature)
+        };
+        let amount = if tx.amount.is_finite() && tx.amount > 0.0 {
+            Ok(())
+        } else {
+            Err(TxRejectReason::InvalidAmount)
+        };
+        let fee = if tx.fee.is_finite() && tx.fee >= 0.0 {
+            Ok(())
+        } else {
+            Err(TxRejectReason::InvalidFee)
+        };
+        let min_fee = match self.genesis_config.activations.min_fee.as_ref().and_then(|a| a.at(height)) {
+            Some(min_fee) if tx.fee < *min_fee => Err(TxRejectReason::FeeTooLow),
+            _ => Ok(()),
+        };
+        let pow = match self.genesis_config.tx_pow_difficulty {
+            Some(difficulty) if !tx.has_pow(difficulty) => Err(TxRejectReason::InsufficientWork),
+            _ => Ok(()),
+        };
+        let timestamp = if tx.created_at <= Utc::now().timestamp() + MAX_TX_FUTURE_DRIFT_SECS {
+            Ok(())
+        } else {
+            Err(TxRejectReason::FutureTimestamp)
+        };
+        // only rules out nonces already used; that a block takes them in sequence
+        // is checked per block by are_nonces_sequential
+        let nonce = if tx.nonce >= Blockchain::next_nonce_on(&tx.sender, history) {
+            Ok(())
+        } else {
+            Err(TxRejectReason::StaleNonce)
+        };
+        // the chain balance alone: pending credits may never confirm, and a block's
+        // combined spends are checked per block by are_spends_funded
+        let balance = if Blockchain::balance_on(&tx.sender, history) - tx.amount - tx.fee >= -BALANCE_EPSILON {
+            Ok(())
+        } else {
+            Err(TxRejectReason::InsufficientFunds)
+        };
+        vec![
+            ("address", address),
+            ("chain_id", chain_id),
+            ("signature", signature),
+            ("amount", amount),
+            ("fee", fee),
+            ("min_fee", min_fee),
+            ("pow", pow),
+            ("timestamp", timestamp),
+            ("nonce", nonce),
+            ("balance", balance),
+        ]
+    }
+
+    pub fn validate_transaction(&self, tx: &Transaction) -> Result<(), TxRejectReason> {
+        self.validate_transaction_on(tx, &self.blocks)
+    }
+
+    pub fn validate_transaction_on(&self, tx: &Transaction, history: &[Block]) -> Result<(), TxRejectReason> {
+        self.transaction_checks_on(tx, history)
+            .into_iter()
+            .try_for_each(|(_, result)| result)
+    }
+
+    /* Leading zero bits required of the block at `height` on the local chain */
+    pub fn expected_difficulty(&self, height: u64) -> u32 {
+        self.expected_difficulty_on(&self.blocks, height)
+    }
+
+    /* Same, on top of `history`. Windows start after genesis (whose timestamp is
+       fixed): at each height 1 + k * window_blocks, difficulty moves by
+       log2(target / actual time) over the window just ended, rounded and clamped
+       to MAX_RETARGET_STEP_BITS. */
+    fn expected_difficulty_on(&self, history: &[Block], height: u64) -> u32 {
+        let retarget = match &self.genesis_config.retarget {
+            Some(retarget) if retarget.window_blocks >= 2 => retarget,
+            _ => return self.difficulty,
+        };
+        let window = retarget.window_blocks as usize;
+        let height = (height as usize).min(history.len());
+        let target = (retarget.target_block_secs * (retarget.window_blocks - 1)) as f64;
+        let mut difficulty = self.difficulty as i64;
+        let mut boundary = window + 1;
+        while boundary <= height {
+            let first = &history[boundary - window];
+            let last = &history[boundary - 1];
+            let actual = (last.timestamp - first.timestamp).max(1) as f64;
+            let step = ((target / actual).log2().round() as i64).clamp(-MAX_RETARGET_STEP_BITS, MAX_RETARGET_STEP_BITS);
+            difficulty = (difficulty + step).clamp(0, 256);
+            boundary += window;
+        }
+        difficulty as u32
+    }
+
+    pub fn is_block_valid(&self, block: &Block, previous_block: &Block, difficulty: u32) -> Result<(), BlockValidationError> {
+        // the work is judged on the hash of the actual header, so a claimed hash
+        // with enough zeros but a different preimage fails as a mismatch first
+        let hash = calculate_hash(
+            block.id,
+            block.timestamp,
+            &block.previous_hash,
+            &block.data,
+            &block.merkle_root,
+            block.nonce,
+        );
+        if block.id != previous_block.id + 1 {
+            return Err(BlockValidationError::NotNextId { id: block.id, parent_id: previous_block.id });
+        }else if block.previous_hash != previous_block.hash {
+            return Err(BlockValidationError::PreviousHashMismatch);
+        }else if block.timestamp <= previous_block.timestamp {
+            return Err(BlockValidationError::TimestampNotIncreasing);
+        }else if block.timestamp > Utc::now().timestamp() + MAX_BLOCK_FUTURE_DRIFT_SECS {
+            return Err(BlockValidationError::FutureTimestamp);
+        }else if hex::encode(&hash) != block.hash {
+            return Err(BlockValidationError::HashMismatch);
+        }else if !self.consensus.validate_block(block, &hash, difficulty) {
+            return Err(BlockValidationError::InsufficientWork);
+        }else if block.transactions.len() > MAX_TRANSACTIONS_PER_BLOCK {
+            return Err(BlockValidationError::TooManyTransactions(block.transactions.len()));
+        }else if !is_canonical_order(&block.transactions) {
+            return Err(BlockValidationError::NonCanonicalOrder);
+        }else if block.transactions.iter().map(Transaction::id).collect::<HashSet<_>>().len() != block.transactions.len() {
+            // nonce sequencing would catch this too, but a block must not double-apply a transfer on any path
+            return Err(BlockValidationError::DuplicateTransaction);
+        }else if block.merkle_root != merkle_root(&block.transactions) {
+            return Err(BlockValidationError::MerkleRootMismatch);
+        }
+        Ok(())
+    }
+    /* Full check of `block` as the next block after `history` (which ends with its
+       parent): the stateless checks above plus rules that depend on earlier blocks */
+    pub fn is_block_valid_on(&self, block: &Block, history: &[Block]) -> Result<(), BlockValidationError> {
+        let previous_block = history.last().expect("there is at least one block.");
+        let key = (
+            hash_string(&serde_json::to_string(block).expect("can jsonify block")),
+            previous_block.hash.clone(),
+        );
+        if self.validated.lock().expect("validation cache lock").contains(&key) {
+            self.validation_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        // ids are continuous (checked in is_block_valid), so the height is history.len()
+        self.is_block_valid(block, previous_block, self.expected_difficulty_on(history, history.len() as u64))?;
+        self.check_transactions_on(block, history)?;
+        if !self.is_coinbase_valid(block) {
+            return Err(BlockValidationError::InvalidCoinbase);
+        }
+        if !self.is_within_spend_limit(block, history) {
+            return Err(BlockValidationError::SpendLimitExceeded);
+        }
+        if !self.are_spends_funded(block, history) {
+            return Err(BlockValidationError::Overspend);
+        }
+        if !self.are_nonces_sequential(block, history) {
+            return Err(BlockValidationError::NonceOutOfSequence);
+        }
+        let mut validated = self.validated.lock().expect("validation cache lock");
+        if validated.len() >= VALIDATION_CACHE_CAPACITY {
+            validated.clear();
+        }
+        validated.insert(key);
+        Ok(())
+    }
+
+    /* How often is_block_valid_on answered from the cache */
+    pub fn validation_cache_hits(&self) -> u64 {
+        self.validation_cache_hits.load(Ordering::Relaxed)
+    }
+
+    /* Each sender's transactions in the block use exactly its next nonces, with
+       no gaps or repeats. Block order is by txid, so nonces are compared sorted. */
+    fn are_nonces_sequential(&self, block: &Block, history: &[Block]) -> bool {
+        let mut nonces: HashMap<&str, Vec<u64>> = HashMap::new();
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            nonces.entry(tx.sender.as_str()).or_default().push(tx.nonce);
+        }
+        for (sender, mut used) in nonces {
+            used.sort_unstable();
+            let next = Blockchain::next_nonce_on(sender, history);
+            if used.iter().enumerate().any(|(i, nonce)| *nonce != next + i as u64) {
+                warn_limited!(
+                    target: logging::CONSENSUS,
+                    "block with id#{} has out-of-sequence nonces for {} (expected from {})",
+                    block.id, sender, next
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    /* No sender may spend more than it holds. Balances start from `history` and
+       move with each transaction in block order (the coinbase credit comes
+       first), so two spends that are each affordable can still overspend
+       together. */
+    fn are_spends_funded(&self, block: &Block, history: &[Block]) -> bool {
+        let mut balances: HashMap<&str, f64> = HashMap::new();
+        for tx in &block.transactions {
+            if tx.is_coinbase() {
+                *balances
+                    .entry(tx.receiver.as_str())
+                    .or_insert_with(|| Blockchain::balance_on(&tx.receiver, history)) += tx.amount;
+                continue;
+            }
+            let sender = balances
+                .entry(tx.sender.as_str())
+                .or_insert_with(|| Blockchain::balance_on(&tx.sender, history));
+            *sender -= tx.amount + tx.fee;
+            if *sender < -BALANCE_EPSILON {
+                warn_limited!(
+                    target: logging::CONSENSUS,
+                    "block with id#{} has transaction {} overspending {}",
+                    block.id, tx.id(), tx.sender
+                );
+                return false;
+            }
+            *balances
+                .entry(tx.receiver.as_str())
+                .or_insert_with(|| Blockchain::balance_on(&tx.receiver, history)) += tx.amount;
+        }
+        true
+    }
+
+    /* Exactly one coinbase, first in the block, paying exactly the block reward
+       plus the miner's share of the block's fees */
+    fn is_coinbase_valid(&self, block: &Block) -> bool {
+        let coinbases = block.transactions.iter().filter(|tx| tx.is_coinbase()).count();
+        let coinbase = match block.transactions.first() {
+            Some(tx) if tx.is_coinbase() && coinbases == 1 => tx,
+            _ => {
+                warn_limited!(target: logging::CONSENSUS, "block with id#{} needs exactly one coinbase, first", block.id);
+                return false;
+            }
+        };
+        let reward = self.coinbase_amount(block.id, &block.transactions);
+        if (coinbase.amount - reward).abs() > BALANCE_EPSILON || coinbase.fee != 0.0 {
+            warn_limited!(
+                target: logging::CONSENSUS,
+                "block with id#{} pays a coinbase of {}, expected {}",
+                block.id, coinbase.amount, reward
+            );
+            return false;
+        }
+        if !is_valid_address(&coinbase.receiver) {
+            warn_limited!(target: logging::CONSENSUS, "block with id#{} pays its coinbase to an invalid address", block.id);
             return false;
         }
         true
     }
-    fn is_chain_valid(&self, chain: &[Block]) -> bool {
-        for i in 0..chain.len() {
-            if i == 0 {
-                continue; // skip the genesis block
+
+    /* Ordinary transactions only; the coinbase has its own rules above */
+    fn check_transactions_on(&self, block: &Block, history: &[Block]) -> Result<(), BlockValidationError> {
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            match self.validate_transaction_on(tx, history) {
+                Ok(()) => {}
+                Err(TxRejectReason::InvalidSignature) => return Err(BlockValidationError::BadSignature),
+                Err(reason) => return Err(BlockValidationError::InvalidTransaction(reason)),
             }
-            let first = chain.get(i - 1).expect("has to exist");
-            let second = chain.get(i).expect("has to exist");
-            if !self.is_block_valid(second, first) {
+        }
+        Ok(())
+    }
+
+    fn is_within_spend_limit(&self, block: &Block, history: &[Block]) -> bool {
+        let limit = match &self.genesis_config.spend_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        // the window is this block plus the window_blocks - 1 before it
+        let earlier = limit.window_blocks.saturating_sub(1) as usize;
+        let mut spent: HashMap<&str, f64> = HashMap::new();
+        for tx in history[history.len().saturating_sub(earlier)..]
+            .iter()
+            .flat_map(|b| b.transactions.iter())
+            .filter(|tx| !tx.is_coinbase())
+        {
+            *spent.entry(tx.sender.as_str()).or_insert(0.0) += tx.amount;
+        }
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let total = spent.entry(tx.sender.as_str()).or_insert(0.0);
+            *total += tx.amount;
+            if *total > limit.max_amount {
+                warn_limited!(
+                    target: logging::CONSENSUS,
+                    "block with id#{}: {} sent {} within {} blocks, over the limit of {}",
+                    block.id, tx.sender, total, limit.window_blocks, limit.max_amount
+                );
                 return false;
             }
         }
         true
     }
-    pub(crate) fn choose_chain(&mut self, local: Vec<Block>, remote: Vec<Block>) -> Vec<Block> {
+
+    /* Index of the first block that fails validation, if any */
+    fn first_invalid_block(&self, chain: &[Block]) -> Option<usize> {
+        if chain.is_empty() {
+            // a chain without a genesis block isn't a chain
+            return Some(0);
+        }
+        for i in 0..chain.len() {
+            if i == 0 {
+                if !self.is_genesis_valid(&chain[0]) {
+                    return Some(0);
+                }
+                continue;
+            }
+            if let Err(e) = self.is_block_valid_on(&chain[i], &chain[..i]) {
+                warn_limited!(target: logging::CONSENSUS, "block with id#{} is invalid: {}", chain[i].id, e);
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    fn is_chain_valid(&self, chain: &[Block]) -> bool {
+        self.first_invalid_block(chain).is_none()
+    }
+
+    /* Re-validate the whole local chain; Err carries the index of the first bad block */
+    pub fn audit(&self) -> Result<(), usize> {
+        match self.first_invalid_block(&self.blocks) {
+            Some(index) => Err(index),
+            None => Ok(()),
+        }
+    }
+
+    /* Cut the chain back to its valid prefix; returns how many blocks were dropped.
+       A bad genesis leaves nothing to keep, so genesis is rebuilt. */
+    pub fn truncate_to_valid(&mut self) -> usize {
+        let before = self.blocks.len();
+        while let Err(index) = self.audit() {
+            warn!(target: logging::CONSENSUS, "truncating chain at invalid block #{}", index);
+            self.blocks.truncate(index);
+            if self.blocks.is_empty() {
+                self.genesis();
+            }
+        }
+        let dropped = before.saturating_sub(self.blocks.len());
+        if dropped > 0 {
+            self.persist();
+        }
+        dropped
+    }
+    /* Height of the first block where the two chains disagree, i.e. the length
+       of their common prefix. None if one chain is a prefix of the other. */
+    pub fn fork_point(local: &[Block], remote: &[Block]) -> Option<usize> {
+        let common = Blockchain::common_prefix_len(local, remote);
+        if common == local.len() || common == remote.len() {
+            None
+        } else {
+            Some(common)
+        }
+    }
+
+    fn common_prefix_len(local: &[Block], remote: &[Block]) -> usize {
+        local
+            .iter()
+            .zip(remote.iter())
+            .take_while(|(l, r)| l.hash == r.hash)
+            .count()
+    }
+
+    /* Side-by-side comparison of two chains: shared prefix and each side's own blocks */
+    pub fn diff_chains(local: &[Block], remote: &[Block]) -> ChainDiff {
+        let common = Blockchain::common_prefix_len(local, remote);
+        ChainDiff {
+            common_ancestor: common.checked_sub(1).map(|i| local[i].id),
+            local_only: local[common..].iter().map(|b| b.hash.clone()).collect(),
+            remote_only: remote[common..].iter().map(|b| b.hash.clone()).collect(),
+        }
+    }
+
+    pub(crate) fn choose_chain(&mut self, local: Vec<Block>, remote: Vec<Block>) -> Result<Vec<Block>, ChainSelectionError> {
         let is_local_valid = self.is_chain_valid(&local);
         let is_remote_valid = self.is_chain_valid(&remote);
 
-        if is_local_valid && is_remote_valid {
-            if local.len() >= remote.len() {
-                local
-            }else {
-                remote
-            }
+        if Blockchain::fork_point(&local, &remote) == Some(1) {
+            // both sides built their own block 1 on a shared genesis: usually two
+            // nodes mining before the startup sync finished
+            warn!(
+                target: logging::CONSENSUS,
+                "early fork detected: chains share genesis but diverge at block 1 (local {} vs remote {})",
+                local[1].hash, remote[1].hash
+            );
+        }
+
+        let chosen = if is_local_valid && is_remote_valid {
+            info!(
+                target: logging::CONSENSUS,
+                "choosing between local chain (work {}, {} blocks) and remote chain (work {}, {} blocks)",
+                Blockchain::chain_work(&local), local.len(), Blockchain::chain_work(&remote), remote.len()
+            );
+            self.consensus.select_chain(local.clone(), remote)
         }else if is_remote_valid && !is_local_valid {
             remote
         }else if !is_remote_valid && is_local_valid {
-            local
+            local.clone()
         }else {
-            panic!("local and remote chains are both invalid");
+            return Err(ChainSelectionError::BothInvalid);
+        };
+        self.notify_reorg(&local, &chosen);
+        Ok(chosen)
+    }
+
+    fn notify_reorg(&self, old: &[Block], new: &[Block]) {
+        let common = Blockchain::common_prefix_len(old, new);
+        if common == old.len() {
+            // nothing was dropped, the new chain only extends the old one
+            return;
+        }
+        self.validated.lock().expect("validation cache lock").clear();
+        let event = ReorgEvent {
+            fork_height: common as u64,
+            orphaned_hashes: old[common..].iter().map(|b| b.hash.clone()).collect(),
+            new_tip: new.last().map(|b| b.hash.clone()).unwrap_or_default(),
+        };
+        warn!(
+            target: logging::CONSENSUS,
+            "reorg at height {}: {} blocks orphaned, new tip {}",
+            event.fork_height,
+            event.orphaned_hashes.len(),
+            event.new_tip
+        );
+        // an error only means nobody is subscribed
+        let _ = self.reorg_sender.send(event);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::{Activation, Activations, Retarget, SpendLimit};
+    use crate::key::KeyMaster;
+    use crate::transaction::generate_test_transactions;
+    use once_cell::sync::Lazy;
+
+    /* Keeps every warning logged while the tests run, as (target, message) */
+    struct CapturingLogger;
+
+    static CAPTURED: Lazy<Mutex<Vec<(String, String)>>> = Lazy::new(|| Mutex::new(vec![]));
+    static LOGGER: CapturingLogger = CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED
+                .lock()
+                .unwrap()
+                .push((record.target().to_string(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /* Installs the capturing logger; safe to call from every test that needs it */
+    pub(crate) fn capture_logs() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&LOGGER).expect("no other logger in tests");
+            log::set_max_level(log::LevelFilter::Warn);
+        });
+    }
+
+    /* Whether a warning containing `needle` was logged under `target`. The logger
+       is shared by all tests, so needles should be unique to one. */
+    pub(crate) fn logged(target: &str, needle: &str) -> bool {
+        CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(t, message)| t == target && message.contains(needle))
+    }
+
+
+    /* Blocks are mined for real in tests, at a difficulty that takes a few hundred hashes */
+    pub(crate) const TEST_DIFFICULTY: u32 = 8;
+
+    pub(crate) fn test_chain() -> Blockchain {
+        test_chain_with(GenesisConfig::default())
+    }
+
+    pub(crate) fn test_chain_with(genesis_config: GenesisConfig) -> Blockchain {
+        let mut chain = empty_test_chain();
+        chain.genesis_config = genesis_config;
+        chain.genesis();
+        chain
+    }
+
+    /* `amount` from `from` to a fresh key, signed for `chain` with the sender's next nonce */
+    pub(crate) fn transfer(chain: &Blockchain, from: &KeyMaster, amount: f64, fee: f64) -> Transaction {
+        let mut tx = Transaction::new(from.public_key.clone(), KeyMaster::new().public_key, amount, fee);
+        tx.nonce = chain.next_nonce(&from.public_key);
+        tx.chain_id = chain.chain_id().unwrap();
+        tx.sign(from).unwrap();
+        tx
+    }
+
+
+    /* The next block on `chain` paying its coinbase to `miner`; not added */
+    pub(crate) fn next_block(chain: &Blockchain, miner: &KeyMaster, transactions: Vec<Transaction>) -> Block {
+        let tip = chain.blocks.last().unwrap();
+        let height = chain.blocks.len() as u64;
+        let reward = chain.coinbase_amount(height, &transactions);
+        let mut all = vec![Transaction::coinbase(miner.public_key.clone(), reward, height)];
+        all.extend(transactions);
+        Block::new(height, tip.hash.clone(), tip.timestamp, "test".to_string(), all, chain.expected_difficulty(height))
+    }
+
+    pub(crate) fn mine(chain: &mut Blockchain, miner: &KeyMaster, transactions: Vec<Transaction>) -> Block {
+        let block = next_block(chain, miner, transactions);
+        chain.try_add_block(block.clone()).unwrap();
+        block
+    }
+
+
+    /* Empty directory under the system temp dir, unique to the test named `name` */
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("waytoblockchain-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /* Like test_chain, without a genesis block, for loading one from disk */
+    pub(crate) fn empty_test_chain() -> Blockchain {
+        let mut chain = Blockchain::new();
+        chain.difficulty = TEST_DIFFICULTY;
+        chain
+    }
+
+    #[test]
+    fn validation_warnings_use_the_consensus_target() {
+        capture_logs();
+        let mut chain = Blockchain::new();
+        chain.difficulty = 0;
+        chain.genesis();
+
+        let mut genesis = chain.blocks[0].clone();
+        genesis.id = 722;
+        assert!(!chain.is_genesis_valid(&genesis));
+
+        assert!(logged(logging::CONSENSUS, "genesis block has id#722"));
+        assert!(!logged(logging::NET, "genesis block has id#722"));
+    }
+
+    fn check(checks: &[(&str, Result<(), TxRejectReason>)], name: &str) -> Result<(), TxRejectReason> {
+        checks.iter().find(|(n, _)| *n == name).expect("no such check").1.clone()
+    }
+
+    #[test]
+    fn chain_id_follows_genesis() {
+        let chain = test_chain();
+        assert_eq!(chain.chain_id(), test_chain().chain_id());
+
+        let other = test_chain_with(GenesisConfig { genesis_timestamp: 1_700_000_000, ..GenesisConfig::default() });
+        assert_ne!(chain.chain_id(), other.chain_id());
+
+        // signed for the other network, so not valid on this one
+        let tx = transfer(&other, &KeyMaster::new(), 1.0, 0.0);
+        assert_eq!(check(&chain.transaction_checks(&tx), "chain_id"), Err(TxRejectReason::WrongChain));
+        assert_eq!(check(&other.transaction_checks(&tx), "chain_id"), Ok(()));
+    }
+
+    #[test]
+    fn far_future_created_at_is_refused() {
+        let chain = test_chain();
+        let key = KeyMaster::new();
+        let mut tx = transfer(&chain, &key, 1.0, 0.0);
+        assert_eq!(check(&chain.transaction_checks(&tx), "timestamp"), Ok(()));
+
+        tx.created_at = Utc::now().timestamp() + MAX_TX_FUTURE_DRIFT_SECS + 60;
+        tx.sign(&key).unwrap();
+        assert_eq!(check(&chain.transaction_checks(&tx), "timestamp"), Err(TxRejectReason::FutureTimestamp));
+    }
+
+    #[test]
+    fn csv_has_a_header_and_a_row_per_transaction() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 2.5, 0.5);
+        let block = mine(&mut chain, &key, vec![tx.clone()]);
+
+        let csv = chain.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "block_id,block_timestamp,txid,sender,receiver,amount,fee");
+        // two coinbases and the transfer; genesis has no rows
+        assert_eq!(lines.len(), 4);
+        let row = format!("2,{},{},{},{},2.5,0.5", block.timestamp, tx.id(), tx.sender, tx.receiver);
+        assert!(lines.contains(&row.as_str()));
+    }
+
+    #[test]
+    fn divergence_at_block_one_is_an_early_fork() {
+        capture_logs();
+        let mut local = test_chain();
+        let mut remote = test_chain();
+        let local_block = mine(&mut local, &KeyMaster::new(), vec![]);
+        let remote_block = mine(&mut remote, &KeyMaster::new(), vec![]);
+        assert_eq!(Blockchain::fork_point(&local.blocks, &remote.blocks), Some(1));
+
+        local.choose_chain(local.blocks.clone(), remote.blocks.clone()).unwrap();
+        assert!(logged(logging::CONSENSUS, &format!("early fork detected: chains share genesis but diverge at block 1 (local {}", local_block.hash)));
+        assert!(logged(logging::CONSENSUS, &remote_block.hash));
+    }
+
+    #[test]
+    fn every_failing_check_is_reported() {
+        let chain = test_chain();
+        let mut tx = transfer(&chain, &KeyMaster::new(), 0.0, 0.0);
+        tx.memo = "changed after signing".to_string();
+
+        let failed: Vec<_> = chain
+            .transaction_checks(&tx)
+            .into_iter()
+            .filter(|(_, result)| result.is_err())
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                ("signature", Err(TxRejectReason::InvalidSignature)),
+                ("amount", Err(TxRejectReason::InvalidAmount)),
+            ]
+        );
+        // validate_transaction stops at the first
+        assert_eq!(chain.validate_transaction(&tx), Err(TxRejectReason::InvalidSignature));
+    }
+
+    #[test]
+    fn gzip_chain_file_round_trips() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        mine(&mut chain, &key, vec![]);
+        let path = temp_dir("gzip_round_trip").join("chain.json.gz");
+
+        chain.save_to_file(&path).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(&[0x1f, 0x8b]));
+
+        let mut loaded = empty_test_chain();
+        loaded.load_blocks(&path).unwrap();
+        let hashes = |chain: &Blockchain| chain.blocks.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(hashes(&loaded), hashes(&chain));
+    }
+
+    #[test]
+    fn audit_finds_a_broken_link() {
+        let mut chain = test_chain();
+        assert_eq!(chain.audit(), Ok(()));
+
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        mine(&mut chain, &key, vec![]);
+        assert_eq!(chain.audit(), Ok(()));
+
+        chain.blocks[2].previous_hash = "ab".repeat(32);
+        assert_eq!(chain.audit(), Err(2));
+    }
+
+    #[test]
+    fn transactions_per_second_over_recent_blocks() {
+        let mut chain = test_chain();
+        assert_eq!(chain.transactions_per_second(TPS_WINDOW_BLOCKS), None);
+
+        // only timestamps and transaction counts matter here
+        let tx = Transaction::new("a".to_string(), "b".to_string(), 1.0, 0.0);
+        for (timestamp, count) in [(1_000, 7), (1_010, 4), (1_020, 6)] {
+            let mut block = chain.blocks[0].clone();
+            block.timestamp = timestamp;
+            block.transactions = vec![tx.clone(); count];
+            chain.blocks.push(block);
+        }
+        // the first block in the window only opens it: (4 + 6) / 20s
+        assert_eq!(chain.transactions_per_second(TPS_WINDOW_BLOCKS), Some(0.5));
+        assert_eq!(chain.transactions_per_second(2), Some(0.6));
+        assert_eq!(chain.transactions_per_second(1), None);
+    }
+
+    #[test]
+    fn generated_transactions_validate_and_mine() {
+        let mut chain = test_chain();
+        let wallet = KeyMaster::new();
+        mine(&mut chain, &wallet, vec![]);
+
+        let chain_id = chain.chain_id().unwrap();
+        let transactions = generate_test_transactions(&wallet, &chain_id, 5, 0, None).unwrap();
+        assert_eq!(transactions.len(), 5);
+        for tx in transactions {
+            assert_eq!(chain.validate_transaction(&tx), Ok(()));
+            assert!(chain.mempool.add_transaction(tx));
+        }
+
+        let template = chain.block_template();
+        assert_eq!(template.len(), 5);
+        mine(&mut chain, &wallet, template);
+        assert_eq!(chain.next_nonce(&wallet.public_key), 5);
+    }
+
+    #[test]
+    fn burning_reduces_circulating_supply() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        assert_eq!(chain.circulating_supply(), 10.0);
+
+        let mut burn = Transaction::new(key.public_key.clone(), BURN_ADDRESS.to_string(), 4.0, 0.0);
+        burn.chain_id = chain.chain_id().unwrap();
+        burn.sign(&key).unwrap();
+        mine(&mut chain, &key, vec![burn]);
+
+        assert_eq!(chain.total_issued(), 20.0);
+        assert_eq!(chain.total_burned(), 4.0);
+        assert_eq!(chain.circulating_supply(), 16.0);
+        assert!(!chain.account_balances().contains_key(BURN_ADDRESS));
+    }
+
+    #[test]
+    fn truncation_keeps_the_prefix_before_a_corrupted_block() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        for _ in 0..4 {
+            mine(&mut chain, &key, vec![]);
         }
+        let kept: Vec<String> = chain.blocks[..2].iter().map(|b| b.hash.clone()).collect();
+        chain.blocks[2].data = "corrupted".to_string();
+
+        assert_eq!(chain.truncate_to_valid(), 3);
+        assert_eq!(chain.blocks.iter().map(|b| b.hash.clone()).collect::<Vec<_>>(), kept);
+        assert_eq!(chain.audit(), Ok(()));
+        assert_eq!(chain.truncate_to_valid(), 0);
+    }
+
+    #[test]
+    fn spend_limit_applies_within_the_window() {
+        let limit = SpendLimit { max_amount: 5.0, window_blocks: 2 };
+        let mut chain = test_chain_with(GenesisConfig { spend_limit: Some(limit), ..GenesisConfig::default() });
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 4.0, 0.0);
+        mine(&mut chain, &key, vec![tx]);
+
+        // 4 + 2 within two blocks is over the limit
+        let over = next_block(&chain, &key, vec![transfer(&chain, &key, 2.0, 0.0)]);
+        assert_eq!(chain.try_add_block(over), Err(BlockValidationError::SpendLimitExceeded));
+
+        // 4 + 1 is exactly the limit
+        let tx = transfer(&chain, &key, 1.0, 0.0);
+        mine(&mut chain, &key, vec![tx]);
+        // the 4 has left the window
+        let tx = transfer(&chain, &key, 4.0, 0.0);
+        mine(&mut chain, &key, vec![tx]);
+    }
+
+    #[test]
+    fn half_of_each_fee_is_burned() {
+        let mut chain = test_chain_with(GenesisConfig { burn_fraction: 0.5, ..GenesisConfig::default() });
+        let key = KeyMaster::new();
+        let miner = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+
+        let tx = transfer(&chain, &key, 3.0, 2.0);
+        let block = mine(&mut chain, &miner, vec![tx]);
+        // the reward plus half the fee
+        assert_eq!(block.transactions[0].amount, 11.0);
+        assert_eq!(chain.balance_of(&miner.public_key), 11.0);
+        assert_eq!(chain.balance_of(&key.public_key), 5.0);
+        assert_eq!(chain.total_burned(), 1.0);
+        assert_eq!(chain.total_issued(), 20.0);
+        assert_eq!(chain.circulating_supply(), 19.0);
+
+        // a coinbase claiming the whole fee is refused
+        let tip = chain.blocks.last().unwrap();
+        let greedy = Block::new(
+            3,
+            tip.hash.clone(),
+            tip.timestamp,
+            "test".to_string(),
+            vec![Transaction::coinbase(miner.public_key.clone(), 12.0, 3), transfer(&chain, &key, 1.0, 2.0)],
+            TEST_DIFFICULTY,
+        );
+        assert_eq!(chain.try_add_block(greedy), Err(BlockValidationError::InvalidCoinbase));
+    }
+
+    #[test]
+    fn finality_depends_on_confirmations() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        for _ in 0..4 {
+            mine(&mut chain, &key, vec![]);
+        }
+        // heights 0..=4; the tip has one confirmation
+        assert_eq!(chain.confirmations(4), 1);
+        assert!(chain.is_final(4, 1));
+        assert!(!chain.is_final(4, 2));
+        assert!(chain.is_final(2, 3));
+        assert!(!chain.is_final(3, 3));
+        assert!(!chain.is_final(9, 1));
+        assert_eq!(chain.safe_tip(3).map(|b| b.id), Some(2));
+        assert!(chain.safe_tip(6).is_none());
+    }
+
+    #[test]
+    fn mined_transaction_replays_against_its_pre_state() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 6.0, 0.0);
+        mine(&mut chain, &key, vec![tx.clone()]);
+        mine(&mut chain, &key, vec![]);
+
+        let (height, _) = chain.find_transaction(&tx.id()).unwrap();
+        let pre_state = &chain.blocks[..height as usize];
+        assert!(chain.transaction_checks_on(&tx, pre_state).iter().all(|(_, result)| result.is_ok()));
+        // against today's chain its nonce is used
+        assert_eq!(chain.validate_transaction(&tx), Err(TxRejectReason::StaleNonce));
+    }
+
+    #[test]
+    fn diff_chains_splits_at_the_common_ancestor() {
+        let mut local = test_chain();
+        let mut remote = test_chain();
+        let a1 = mine(&mut local, &KeyMaster::new(), vec![]);
+        let a2 = mine(&mut local, &KeyMaster::new(), vec![]);
+        let b1 = mine(&mut remote, &KeyMaster::new(), vec![]);
+
+        let diff = Blockchain::diff_chains(&local.blocks, &remote.blocks);
+        assert_eq!(diff.common_ancestor, Some(0));
+        assert_eq!(diff.local_only, vec![a1.hash, a2.hash]);
+        assert_eq!(diff.remote_only, vec![b1.hash]);
+
+        let same = Blockchain::diff_chains(&local.blocks, &local.blocks);
+        assert_eq!(same.common_ancestor, Some(2));
+        assert!(same.local_only.is_empty() && same.remote_only.is_empty());
+
+        let foreign = test_chain_with(GenesisConfig { genesis_timestamp: 1, ..GenesisConfig::default() });
+        let diff = Blockchain::diff_chains(&local.blocks, &foreign.blocks);
+        assert_eq!(diff.common_ancestor, None);
+        assert_eq!(diff.local_only.len(), 3);
+    }
+
+    #[test]
+    fn transaction_pow_is_required_when_configured() {
+        let chain = test_chain_with(GenesisConfig { tx_pow_difficulty: Some(8), ..GenesisConfig::default() });
+        let key = KeyMaster::new();
+        let mut tx = transfer(&chain, &key, 1.0, 0.0);
+        // pow_nonce 0 passing by chance would make this flaky, so make sure it doesn't
+        while tx.has_pow(8) {
+            tx.pow_nonce += 1;
+        }
+        assert_eq!(check(&chain.transaction_checks(&tx), "pow"), Err(TxRejectReason::InsufficientWork));
+
+        tx.solve_pow(8);
+        assert_eq!(check(&chain.transaction_checks(&tx), "pow"), Ok(()));
+        // pow_nonce isn't signed, solving afterwards keeps the signature
+        assert!(tx.verify());
+    }
+
+    #[test]
+    fn reorg_event_names_the_fork_and_orphans() {
+        let mut local = test_chain();
+        let mut remote = test_chain();
+        let a1 = mine(&mut local, &KeyMaster::new(), vec![]);
+        let key = KeyMaster::new();
+        for _ in 0..3 {
+            mine(&mut remote, &key, vec![]);
+        }
+        let mut reorgs = local.subscribe_reorgs();
+
+        let chosen = local.choose_chain(local.blocks.clone(), remote.blocks.clone()).unwrap();
+        assert_eq!(chosen.len(), 4);
+        let event = reorgs.try_recv().unwrap();
+        assert_eq!(event.fork_height, 1);
+        assert_eq!(event.orphaned_hashes, vec![a1.hash]);
+        assert_eq!(event.new_tip, remote.blocks[3].hash);
+
+        // extending the chain drops nothing, so there is no event
+        local.apply_reorg(chosen);
+        mine(&mut remote, &key, vec![]);
+        local.choose_chain(local.blocks.clone(), remote.blocks.clone()).unwrap();
+        assert!(reorgs.try_recv().is_err());
+    }
+
+    #[test]
+    fn same_genesis_config_gives_same_genesis() {
+        let config = GenesisConfig { genesis_timestamp: 1_600_000_000, burn_fraction: 0.25, ..GenesisConfig::default() };
+        let first = test_chain_with(config.clone());
+        let second = test_chain_with(config);
+        assert_eq!(first.blocks[0].hash, second.blocks[0].hash);
+        assert_eq!(first.blocks[0].timestamp, 1_600_000_000);
+        assert_ne!(first.blocks[0].hash, test_chain().blocks[0].hash);
+    }
+
+    #[test]
+    fn changing_an_amount_after_mining_invalidates_the_block() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        let block = next_block(&chain, &key, vec![transfer(&chain, &key, 1.0, 0.0)]);
+
+        let mut tampered = block.clone();
+        tampered.transactions[1].amount = 9.0;
+        assert_eq!(chain.is_block_valid_on(&tampered, &chain.blocks), Err(BlockValidationError::MerkleRootMismatch));
+
+        // recommitting to the new transactions changes the header under the hash
+        tampered.merkle_root = merkle_root(&tampered.transactions);
+        assert_eq!(chain.is_block_valid_on(&tampered, &chain.blocks), Err(BlockValidationError::HashMismatch));
+
+        assert_eq!(chain.try_add_block(block), Ok(()));
+    }
+
+    #[test]
+    fn min_fee_applies_from_its_activation_height() {
+        let activations = Activations { min_fee: Some(Activation { height: 3, value: 0.5 }) };
+        let chain = test_chain_with(GenesisConfig { activations, ..GenesisConfig::default() });
+        let cheap = transfer(&chain, &KeyMaster::new(), 1.0, 0.1);
+        let enough = transfer(&chain, &KeyMaster::new(), 1.0, 0.5);
+
+        // the rule reads only the height, which is history.len()
+        let history = |height: usize| vec![chain.blocks[0].clone(); height];
+        assert_eq!(check(&chain.transaction_checks_on(&cheap, &history(2)), "min_fee"), Ok(()));
+        assert_eq!(check(&chain.transaction_checks_on(&cheap, &history(3)), "min_fee"), Err(TxRejectReason::FeeTooLow));
+        assert_eq!(check(&chain.transaction_checks_on(&cheap, &history(4)), "min_fee"), Err(TxRejectReason::FeeTooLow));
+        assert_eq!(check(&chain.transaction_checks_on(&enough, &history(3)), "min_fee"), Ok(()));
+    }
+
+    #[test]
+    fn block_logged_before_a_crash_is_recovered() {
+        let dir = temp_dir("wal_recovery");
+        let (chain_file, wal_file) = (dir.join("chain.json"), dir.join("chain.wal"));
+        let mut chain = test_chain();
+        chain.chain_file = Some(chain_file.clone());
+        chain.wal = Some(Wal::open(&wal_file).unwrap());
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+
+        // the crash: logged, but neither appended nor saved to the chain file
+        let block = next_block(&chain, &key, vec![]);
+        chain.wal.as_mut().unwrap().append(std::slice::from_ref(&block)).unwrap();
+        drop(chain);
+
+        let mut restarted = empty_test_chain();
+        restarted.load_blocks(&chain_file).unwrap();
+        assert_eq!(restarted.blocks.len(), 2);
+        restarted.wal = Some(Wal::open(&wal_file).unwrap());
+        assert_eq!(restarted.replay_wal(), 1);
+        assert_eq!(restarted.blocks.last().unwrap().hash, block.hash);
+        assert_eq!(restarted.audit(), Ok(()));
+    }
+
+    #[test]
+    fn spends_must_be_funded_together() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        // mined by someone else: the coinbase mustn't fund the spends
+        let miner = KeyMaster::new();
+
+        let funded = next_block(&chain, &miner, vec![transfer(&chain, &key, 6.0, 0.0)]);
+        assert!(chain.are_spends_funded(&funded, &chain.blocks));
+
+        let overspend = next_block(&chain, &miner, vec![transfer(&chain, &key, 10.5, 0.0)]);
+        assert!(!chain.are_spends_funded(&overspend, &chain.blocks));
+
+        // each affordable alone, not both
+        let first = transfer(&chain, &key, 6.0, 0.0);
+        let mut second = transfer(&chain, &key, 5.0, 0.0);
+        second.nonce += 1;
+        second.sign(&key).unwrap();
+        let combined = next_block(&chain, &miner, vec![first, second]);
+        assert!(!chain.are_spends_funded(&combined, &chain.blocks));
+        assert_eq!(chain.try_add_block(combined), Err(BlockValidationError::Overspend));
+    }
+
+    #[test]
+    fn ledger_lists_every_funded_account() {
+        let mut chain = test_chain();
+        let (key, miner) = (KeyMaster::new(), KeyMaster::new());
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 3.0, 1.0);
+        mine(&mut chain, &miner, vec![tx.clone()]);
+
+        let balances = chain.account_balances();
+        assert_eq!(balances.len(), 3);
+        assert_eq!(balances[&key.public_key], 6.0);
+        assert_eq!(balances[&tx.receiver], 3.0);
+        assert_eq!(balances[&miner.public_key], 11.0);
+        // everything issued is held by someone
+        assert_eq!(balances.values().sum::<f64>(), chain.circulating_supply());
+
+        // spent-out accounts drop off
+        let spend_all = transfer(&chain, &key, 5.0, 1.0);
+        mine(&mut chain, &miner, vec![spend_all]);
+        assert!(!chain.account_balances().contains_key(&key.public_key));
+    }
+
+    #[test]
+    fn backups_rotate_and_restore() {
+        let chain_file = temp_dir("backups").join("chain.json");
+        let mut chain = test_chain();
+        chain.chain_file = Some(chain_file.clone());
+        chain.backups = 2;
+        let key = KeyMaster::new();
+        for _ in 0..3 {
+            mine(&mut chain, &key, vec![]);
+            // backups are named by the millisecond
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(Blockchain::list_backups(&chain_file).unwrap().len(), 2);
+
+        // 1 is the newest, 2 the save before it
+        chain.restore_backup(2).unwrap();
+        assert_eq!(chain.blocks.len(), 3);
+        let mut reloaded = empty_test_chain();
+        reloaded.load_blocks(&chain_file).unwrap();
+        assert_eq!(reloaded.blocks.len(), 3);
+
+        assert!(chain.restore_backup(0).is_err());
+        assert!(chain.restore_backup(9).is_err());
+    }
+
+    #[test]
+    fn skipped_id_and_tampered_genesis_are_refused() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+
+        let mut skipping = next_block(&chain, &key, vec![]);
+        skipping.id = 3;
+        assert_eq!(
+            chain.is_block_valid_on(&skipping, &chain.blocks),
+            Err(BlockValidationError::NotNextId { id: 3, parent_id: 1 })
+        );
+
+        let mut tampered = chain.blocks.clone();
+        tampered[0].data = "Not genesis".to_string();
+        assert!(!chain.is_genesis_valid(&tampered[0]));
+        assert!(!chain.is_chain_valid(&tampered));
+        assert!(chain.is_chain_valid(&chain.blocks));
+    }
+
+    #[test]
+    fn fast_blocks_raise_the_difficulty() {
+        let retarget = Retarget { window_blocks: 2, target_block_secs: 60 };
+        let chain = test_chain_with(GenesisConfig { retarget: Some(retarget), ..GenesisConfig::default() });
+        // retargeting reads only timestamps
+        let history = |interval: i64| {
+            let mut history = chain.blocks.clone();
+            for timestamp in [1_000, 1_000 + interval] {
+                let mut block = chain.blocks[0].clone();
+                block.timestamp = timestamp;
+                history.push(block);
+            }
+            history
+        };
+
+        // the window isn't over before height 3
+        assert_eq!(chain.expected_difficulty_on(&history(1), 2), TEST_DIFFICULTY);
+        // 1s instead of 60s: up, by at most MAX_RETARGET_STEP_BITS
+        assert_eq!(chain.expected_difficulty_on(&history(1), 3), TEST_DIFFICULTY + 2);
+        assert_eq!(chain.expected_difficulty_on(&history(30), 3), TEST_DIFFICULTY + 1);
+        assert_eq!(chain.expected_difficulty_on(&history(60), 3), TEST_DIFFICULTY);
+        assert_eq!(chain.expected_difficulty_on(&history(600), 3), TEST_DIFFICULTY - 2);
+    }
+
+    #[test]
+    fn replayed_transaction_is_refused() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 1.0, 0.0);
+        mine(&mut chain, &key, vec![tx.clone()]);
+
+        assert_eq!(chain.validate_transaction(&tx), Err(TxRejectReason::StaleNonce));
+        let replay = next_block(&chain, &key, vec![tx]);
+        assert_eq!(
+            chain.try_add_block(replay),
+            Err(BlockValidationError::InvalidTransaction(TxRejectReason::StaleNonce))
+        );
+    }
+
+    #[test]
+    fn repeated_validation_hits_the_cache() {
+        let chain = test_chain();
+        let block = next_block(&chain, &KeyMaster::new(), vec![]);
+        assert_eq!(chain.validation_cache_hits(), 0);
+
+        assert_eq!(chain.is_block_valid_on(&block, &chain.blocks), Ok(()));
+        assert_eq!(chain.validation_cache_hits(), 0);
+        assert_eq!(chain.is_block_valid_on(&block, &chain.blocks), Ok(()));
+        assert_eq!(chain.validation_cache_hits(), 1);
+
+        // a changed block is a different entry
+        let mut tampered = block.clone();
+        tampered.data = "tampered".to_string();
+        assert!(chain.is_block_valid_on(&tampered, &chain.blocks).is_err());
+        assert_eq!(chain.validation_cache_hits(), 1);
+    }
+
+    #[test]
+    fn block_over_the_transaction_limit_is_refused() {
+        let chain = test_chain();
+        let miner = KeyMaster::new();
+        let tip = &chain.blocks[0];
+        let mut transactions = vec![Transaction::coinbase(miner.public_key.clone(), 10.0, 1)];
+        // the count is checked before anything about the transactions themselves
+        transactions.extend((0..MAX_TRANSACTIONS_PER_BLOCK).map(|i| Transaction::new("a".to_string(), format!("r{}", i), 1.0, 0.0)));
+        let block = Block::new(1, tip.hash.clone(), tip.timestamp, "test".to_string(), transactions, TEST_DIFFICULTY);
+
+        assert_eq!(
+            chain.is_block_valid_on(&block, &chain.blocks),
+            Err(BlockValidationError::TooManyTransactions(MAX_TRANSACTIONS_PER_BLOCK + 1))
+        );
+    }
+
+    /* `block` mined again with its timestamp replaced */
+    fn with_timestamp(mut block: Block, timestamp: i64) -> Block {
+        block.timestamp = timestamp;
+        let (nonce, hash) = mine_block(block.id, timestamp, &block.previous_hash, &block.data, &block.merkle_root, TEST_DIFFICULTY);
+        block.nonce = nonce;
+        block.hash = hash;
+        block
+    }
+
+    #[test]
+    fn block_timestamps_must_move_forward_but_not_too_far() {
+        let mut chain = test_chain();
+        let miner = KeyMaster::new();
+        let parent = mine(&mut chain, &miner, vec![]);
+        let block = next_block(&chain, &miner, vec![]);
+
+        let future = with_timestamp(block.clone(), Utc::now().timestamp() + MAX_BLOCK_FUTURE_DRIFT_SECS + 600);
+        assert_eq!(chain.is_block_valid_on(&future, &chain.blocks), Err(BlockValidationError::FutureTimestamp));
+
+        for timestamp in [parent.timestamp, parent.timestamp - 1] {
+            let backward = with_timestamp(block.clone(), timestamp);
+            assert_eq!(chain.is_block_valid_on(&backward, &chain.blocks), Err(BlockValidationError::TimestampNotIncreasing));
+        }
+
+        assert_eq!(chain.is_block_valid_on(&block, &chain.blocks), Ok(()));
+    }
+
+    #[test]
+    fn fresh_chains_have_byte_identical_genesis() {
+        let mut first = Blockchain::new();
+        let mut second = Blockchain::new();
+        first.genesis();
+        second.genesis();
+        assert_eq!(serde_json::to_vec(&first.blocks[0]).unwrap(), serde_json::to_vec(&second.blocks[0]).unwrap());
+        assert!(first.is_genesis_valid(&second.blocks[0]));
+    }
+
+    #[test]
+    fn block_with_a_transaction_twice_is_refused() {
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 1.0, 0.0);
+        let block = next_block(&chain, &key, vec![tx.clone(), tx]);
+        assert_eq!(chain.is_block_valid_on(&block, &chain.blocks), Err(BlockValidationError::DuplicateTransaction));
+    }
+
+    #[test]
+    fn child_arriving_before_its_parent_is_attached_with_it() {
+        let miner = KeyMaster::new();
+        let mut source = test_chain();
+        let parent = mine(&mut source, &miner, vec![]);
+        let child = mine(&mut source, &miner, vec![]);
+
+        let mut chain = test_chain();
+        assert_eq!(chain.try_add_block(child.clone()), Ok(()));
+        assert_eq!(chain.blocks.len(), 1);
+        assert_eq!(chain.orphan_count(), 1);
+
+        assert_eq!(chain.try_add_block(parent), Ok(()));
+        assert_eq!(chain.blocks.len(), 3);
+        assert_eq!(chain.blocks[2].hash, child.hash);
+        assert_eq!(chain.orphan_count(), 0);
+    }
+
+    #[test]
+    fn orphans_too_far_ahead_or_with_a_bad_hash_are_not_held() {
+        let miner = KeyMaster::new();
+        let mut source = test_chain();
+        mine(&mut source, &miner, vec![]);
+        let child = mine(&mut source, &miner, vec![]);
+        let mut chain = test_chain();
+
+        let mut far = child.clone();
+        far.id = (chain.blocks.len() + MAX_ORPHAN_BLOCKS + 1) as u64;
+        assert_eq!(chain.try_add_block(far), Err(BlockValidationError::TooFarAhead));
+
+        let mut edited = child;
+        edited.data = "edited".to_string();
+        assert_eq!(chain.try_add_block(edited), Err(BlockValidationError::HashMismatch));
+        assert_eq!(chain.orphan_count(), 0);
+    }
+
+    #[test]
+    fn reorg_returns_dropped_transactions_to_the_mempool() {
+        let funder = KeyMaster::new();
+        let mut local = test_chain();
+        let mut remote = test_chain();
+        let funding = mine(&mut local, &funder, vec![]);
+        remote.try_add_block(funding).unwrap();
+
+        let tx = transfer(&local, &funder, 1.0, 0.0);
+        mine(&mut local, &funder, vec![tx.clone()]);
+        let other = KeyMaster::new();
+        mine(&mut remote, &other, vec![]);
+        mine(&mut remote, &other, vec![]);
+
+        local.apply_reorg(remote.blocks.clone());
+        assert_eq!(local.blocks.len(), 4);
+        assert_eq!(local.blocks[3].hash, remote.blocks[3].hash);
+        assert!(local.mempool.contains(&tx));
+    }
+
+    #[test]
+    fn saved_mempool_round_trips_and_drops_confirmed_transactions() {
+        let mut chain = test_chain();
+        let first = KeyMaster::new();
+        let second = KeyMaster::new();
+        mine(&mut chain, &first, vec![]);
+        mine(&mut chain, &second, vec![]);
+        let confirmed = transfer(&chain, &first, 1.0, 0.0);
+        let pending = transfer(&chain, &second, 1.0, 0.0);
+        assert!(chain.mempool.add_transaction(confirmed.clone()));
+        assert!(chain.mempool.add_transaction(pending.clone()));
+
+        let path = temp_dir("mempool_round_trip").join("mempool.json");
+        chain.mempool.save_to_file(&path).unwrap();
+        let loaded: Vec<String> = Mempool::load_from_file(&path).unwrap().iter().map(Transaction::id).collect();
+        assert_eq!(loaded, vec![confirmed.id(), pending.id()]);
+
+        mine(&mut chain, &first, vec![confirmed.clone()]);
+        chain.mempool = Mempool::new();
+        assert_eq!(chain.restore_mempool(&path).unwrap(), 1);
+        assert!(chain.mempool.contains(&pending));
+        assert!(!chain.mempool.contains(&confirmed));
+    }
+
+    #[test]
+    fn claimed_hash_with_zeros_is_checked_against_the_contents() {
+        let chain = test_chain();
+        let mut block = next_block(&chain, &KeyMaster::new(), vec![]);
+        block.hash = format!("{}{}", "0".repeat(32), &block.hash[32..]);
+        assert_eq!(chain.is_block_valid_on(&block, &chain.blocks), Err(BlockValidationError::HashMismatch));
+    }
+
+    #[test]
+    fn stats_json_deserializes_into_chain_stats() {
+        let mut chain = test_chain();
+        mine(&mut chain, &KeyMaster::new(), vec![]);
+        let json = serde_json::to_string(&chain.stats()).unwrap();
+        let stats: ChainStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(stats.chain_id, chain.chain_id());
+        assert!(stats.chain_id.is_some());
+        assert_eq!(stats.height, 2);
+        assert_eq!(stats.total_transactions, 1);
+        assert_eq!(stats.difficulty, TEST_DIFFICULTY);
+        assert_eq!(stats.issued, chain.total_issued());
     }
 }
@@ -0,0 +1,303 @@
+use libp2p::PeerId;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::block::DEFAULT_DIFFICULTY_BITS;
+use crate::logging;
+use crate::mempool::{DEFAULT_MAX_ANCESTORS, DEFAULT_MAX_DESCENDANTS, DEFAULT_MAX_SIZE, DEFAULT_MAX_TX_AGE_SECS};
+
+/* Where the chain is kept between runs unless `--chain-file` says otherwise */
+pub const DEFAULT_CHAIN_FILE: &str = "blockchain.json";
+/* Where pending transactions are saved on shutdown unless `--mempool-file` says otherwise */
+pub const DEFAULT_MEMPOOL_FILE: &str = "mempool.json";
+/* Any interface, a port picked by the OS */
+pub const DEFAULT_LISTEN_ADDR: &str = "/ip4/0.0.0.0/tcp/0";
+
+pub static CONFIG: Lazy<NodeConfig> = Lazy::new(|| NodeConfig::parse(std::env::args().skip(1)));
+
+/* Node settings taken from the command line */
+#[derive(Debug)]
+pub struct NodeConfig {
+    /* `--allow-list <path>`: file with one PeerId per line. When set, only
+       these peers are meshed with and their messages processed. */
+    pub allow_list: Option<HashSet<PeerId>>,
+    /* `--dev`: enables developer commands such as `gentx` and `partition` */
+    pub dev: bool,
+    /* `--min-peers <n>`: connected peers required before `create b` mines (0 = no limit) */
+    pub min_peers: usize,
+    /* `--genesis <path>`: JSON file with the network's consensus parameters */
+    pub genesis: GenesisConfig,
+    /* `--json-output`: command results are printed to stdout as JSON */
+    pub json_output: bool,
+    /* `--finality-depth <n>`: confirmations after which a block is reported final */
+    pub finality_depth: u64,
+    /* on by default, `--no-mempool-sync` turns it off: newly discovered peers get our pending transactions */
+    pub mempool_sync: bool,
+    /* `--peer-key <path>`: libp2p identity kept across restarts, created on first run */
+    pub peer_key: Option<PathBuf>,
+    /* `--max-ancestors <n>` / `--max-descendants <n>`: mempool dependency chain limits */
+    pub max_ancestors: usize,
+    pub max_descendants: usize,
+    /* `--max-mempool <n>`: pending transactions kept before the cheapest are evicted */
+    pub max_mempool: usize,
+    /* `--mempool-ttl-secs <n>`: pending transactions older than this are purged (0 = never) */
+    pub mempool_ttl_secs: i64,
+    /* `--wal <path>`: write-ahead log that accepted blocks are fsynced to */
+    pub wal: Option<PathBuf>,
+    /* `--chain-file <path>`: loaded on startup if present, rewritten whenever the chain changes */
+    pub chain_file: PathBuf,
+    /* `--mempool-file <path>`: pending transactions are saved here on shutdown and restored on startup */
+    pub mempool_file: PathBuf,
+    /* `--backups <k>`: keep the last k saves of the chain file as timestamped backups */
+    pub backups: usize,
+    /* `--listen <multiaddr>`: address the swarm listens on */
+    pub listen: String,
+    /* `--http <addr>`: serve the read-only HTTP endpoint on this address, e.g. 127.0.0.1:8080 */
+    pub http: Option<String>,
+    /* `--hmac-secret <secret>`: HTTP responses are signed with this, see http.rs */
+    pub hmac_secret: Option<String>,
+    /* `--dial <multiaddr>`, repeatable: bootstrap peers dialed on startup, for
+       networks mDNS can't reach */
+    pub dial: Vec<String>,
+    /* `--difficulty <bits>`: leading zero bits a block hash needs */
+    pub difficulty: u32,
+    /* `--watchdog-secs <n>`: rebuild the swarm after this long without a message
+       while peers are connected (0 = off) */
+    pub watchdog_secs: u64,
+    /* `--wallet-seed <phrase>`: derive the node's wallet from this phrase (see
+       KeyMaster::from_seed) so it, and the coinbase address, survive restarts */
+    pub wallet_seed: Option<String>,
+    /* `--in-memory`: nothing is read from or written to disk; overrides
+       `--chain-file`, `--mempool-file`, `--wal`, `--backups` and `--peer-key` */
+    pub in_memory: bool,
+}
+
+/* Consensus parameters fixed when the network starts; every node must use the same ones */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenesisConfig {
+    pub spend_limit: Option<SpendLimit>,
+    /* share of every transaction fee that is destroyed instead of paid to the miner, 0.0..=1.0 */
+    pub burn_fraction: f64,
+    /* when set, every transaction's pow_hash needs this many leading zero bits */
+    pub tx_pow_difficulty: Option<u32>,
+    /* timestamp of block 0; fixed (0 unless configured) so every node builds the same genesis block and chain id */
+    pub genesis_timestamp: i64,
+    pub activations: Activations,
+    /* when set, block difficulty follows the observed block rate instead of staying at `--difficulty` */
+    pub retarget: Option<Retarget>,
+}
+
+/* Every `window_blocks` blocks the difficulty is moved so that the last window
+   would have taken `target_block_secs` per block */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Retarget {
+    pub window_blocks: u64,
+    pub target_block_secs: u64,
+}
+
+/* Rules that only apply from some block height on, so a running network can
+   tighten consensus at an agreed height instead of all at once */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Activations {
+    /* minimum fee every transaction must pay */
+    pub min_fee: Option<Activation<f64>>,
+}
+
+/* A rule parameter that takes effect at block `height` */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Activation<T> {
+    pub height: u64,
+    pub value: T,
+}
+
+impl<T> Activation<T> {
+    /* The parameter if the rule is in force for a block at `height` */
+    pub fn at(&self, height: u64) -> Option<&T> {
+        if height >= self.height {
+            Some(&self.value)
+        } else {
+            None
+        }
+    }
+}
+
+/* No address may send more than `max_amount` in total within any `window_blocks` consecutive blocks */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpendLimit {
+    pub max_amount: f64,
+    pub window_blocks: u64,
+}
+
+impl GenesisConfig {
+    pub fn load(path: &str) -> Self {
+        let json = fs::read_to_string(path).expect("can read genesis config file");
+        let config: GenesisConfig = serde_json::from_str(&json).expect("genesis config is valid json");
+        // outside this range fees would be minted or the miner would pay for them
+        assert!(
+            (0.0..=1.0).contains(&config.burn_fraction),
+            "genesis config burn_fraction must be between 0.0 and 1.0, got {}",
+            config.burn_fraction
+        );
+        config
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            allow_list: None,
+            dev: false,
+            min_peers: 0,
+            genesis: GenesisConfig::default(),
+            json_output: false,
+            finality_depth: 6,
+            mempool_sync: true,
+            peer_key: None,
+            max_ancestors: DEFAULT_MAX_ANCESTORS,
+            max_descendants: DEFAULT_MAX_DESCENDANTS,
+            max_mempool: DEFAULT_MAX_SIZE,
+            mempool_ttl_secs: DEFAULT_MAX_TX_AGE_SECS,
+            wal: None,
+            chain_file: PathBuf::from(DEFAULT_CHAIN_FILE),
+            mempool_file: PathBuf::from(DEFAULT_MEMPOOL_FILE),
+            backups: 0,
+            listen: DEFAULT_LISTEN_ADDR.to_string(),
+            dial: Vec::new(),
+            http: None,
+            hmac_secret: None,
+            difficulty: DEFAULT_DIFFICULTY_BITS,
+            watchdog_secs: 0,
+            wallet_seed: None,
+            in_memory: false,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Self {
+        let mut config = NodeConfig::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--allow-list" => {
+                    let path = args.next().expect("--allow-list needs a path");
+                    config.allow_list = Some(load_allow_list(&path));
+                }
+                "--dev" => config.dev = true,
+                "--in-memory" => config.in_memory = true,
+                "--json-output" => config.json_output = true,
+                "--no-mempool-sync" => config.mempool_sync = false,
+                "--finality-depth" => {
+                    config.finality_depth = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--finality-depth needs a number");
+                }
+                "--genesis" => {
+                    let path = args.next().expect("--genesis needs a path");
+                    config.genesis = GenesisConfig::load(&path);
+                }
+                "--peer-key" => {
+                    config.peer_key = Some(args.next().expect("--peer-key needs a path").into());
+                }
+                "--max-ancestors" => {
+                    config.max_ancestors = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--max-ancestors needs a number");
+                }
+                "--max-descendants" => {
+                    config.max_descendants = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--max-descendants needs a number");
+                }
+                "--max-mempool" => {
+                    config.max_mempool = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--max-mempool needs a number");
+                }
+                "--mempool-ttl-secs" => {
+                    config.mempool_ttl_secs = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--mempool-ttl-secs needs a number");
+                }
+                "--backups" => {
+                    config.backups = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--backups needs a number");
+                }
+                "--difficulty" => {
+                    config.difficulty = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--difficulty needs a number of bits");
+                }
+                "--watchdog-secs" => {
+                    config.watchdog_secs = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--watchdog-secs needs a number");
+                }
+                "--listen" => {
+                    config.listen = args.next().expect("--listen needs a multiaddr");
+                }
+                "--http" => {
+                    config.http = Some(args.next().expect("--http needs an address"));
+                }
+                "--wallet-seed" => {
+                    let seed = args.next().filter(|seed| !seed.is_empty());
+                    config.wallet_seed = Some(seed.expect("--wallet-seed needs a phrase"));
+                }
+                "--hmac-secret" => {
+                    config.hmac_secret = Some(args.next().expect("--hmac-secret needs a secret"));
+                }
+                "--dial" => {
+                    config.dial.push(args.next().expect("--dial needs a multiaddr"));
+                }
+                "--chain-file" => {
+                    config.chain_file = args.next().expect("--chain-file needs a path").into();
+                }
+                "--mempool-file" => {
+                    config.mempool_file = args.next().expect("--mempool-file needs a path").into();
+                }
+                "--wal" => {
+                    config.wal = Some(args.next().expect("--wal needs a path").into());
+                }
+                "--min-peers" => {
+                    config.min_peers = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--min-peers needs a number");
+                }
+                other => warn!(target: logging::NET, "ignoring unknown argument {}", other),
+            }
+        }
+        config
+    }
+
+    pub fn is_peer_allowed(&self, peer: &PeerId) -> bool {
+        match &self.allow_list {
+            Some(allowed) => allowed.contains(peer),
+            None => true,
+        }
+    }
+}
+
+fn load_allow_list(path: &str) -> HashSet<PeerId> {
+    fs::read_to_string(path)
+        .expect("can read allow-list file")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| PeerId::from_str(line).expect("allow-list contains valid peer ids"))
+        .collect()
+}
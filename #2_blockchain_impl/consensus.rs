@@ -0,0 +1,92 @@
+use crate::warn_limited;
+use crate::block::{block_work, meets_difficulty, Block};
+use crate::logging;
+
+/* The parts of block acceptance that depend on the consensus scheme. Linkage,
+   hash integrity and transaction rules stay in `Blockchain` for every scheme. */
+pub trait Consensus {
+    /* Scheme-specific admission check for a single block. `hash` is recomputed
+       from the block's header by the caller, never the hash the block claims;
+       `difficulty` is the chain's current target in leading zero bits */
+    fn validate_block(&self, block: &Block, hash: &[u8], difficulty: u32) -> bool;
+
+    /* How much a block adds to the weight of the chain it's on */
+    fn block_weight(&self, block: &Block) -> u128;
+
+    /* Pick between two chains that are both valid: the heavier one, then the
+       longer one if they weigh the same; full ties keep the local one */
+    fn select_chain(&self, local: Vec<Block>, remote: Vec<Block>) -> Vec<Block> {
+        let local_weight: u128 = local.iter().map(|b| self.block_weight(b)).sum();
+        let remote_weight: u128 = remote.iter().map(|b| self.block_weight(b)).sum();
+        if (remote_weight, remote.len()) > (local_weight, local.len()) {
+            remote
+        } else {
+            local
+        }
+    }
+}
+
+/* Default scheme: the block hash needs `difficulty` leading zero bits, the chain
+   with the most work (leading zero bits summed over its hashes) wins */
+pub struct ProofOfWork;
+
+impl Consensus for ProofOfWork {
+    fn validate_block(&self, block: &Block, hash: &[u8], difficulty: u32) -> bool {
+        if !meets_difficulty(hash, difficulty) {
+            warn_limited!(target: logging::CONSENSUS, "block with id#{} does not meet the difficulty", block.id);
+            return false;
+        }
+        true
+    }
+
+    fn block_weight(&self, block: &Block) -> u128 {
+        block_work(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* select_chain only weighs hashes, so nothing else needs to be real */
+    fn chain_with_hashes(hashes: &[&str]) -> Vec<Block> {
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(id, hash)| Block {
+                id: id as u64,
+                hash: hash.to_string(),
+                previous_hash: String::new(),
+                timestamp: 0,
+                data: String::new(),
+                merkle_root: String::new(),
+                transactions: vec![],
+                nonce: 0,
+            })
+            .collect()
+    }
+
+    /* A hash with 4 * `zero_digits` bits of work, padded with `fill` (8 or above) */
+    fn hash_with_work(zero_digits: usize, fill: char) -> String {
+        format!("{}{}", "0".repeat(zero_digits), fill.to_string().repeat(64 - zero_digits))
+    }
+
+    #[test]
+    fn heavier_chain_beats_longer_chain() {
+        let genesis = hash_with_work(0, 'f');
+        let (heavy_block, light_block) = (hash_with_work(6, 'f'), hash_with_work(1, 'f'));
+        // 24 + 24 bits of work against 4 + 4 + 4
+        let heavy = chain_with_hashes(&[&genesis, &heavy_block, &heavy_block]);
+        let long = chain_with_hashes(&[&genesis, &light_block, &light_block, &light_block]);
+        assert_eq!(ProofOfWork.select_chain(long.clone(), heavy.clone()).len(), 3);
+        assert_eq!(ProofOfWork.select_chain(heavy, long).len(), 3);
+
+        // equal work: the longer one, then the local one
+        let (twelve, other_twelve, eight) = (hash_with_work(3, 'f'), hash_with_work(3, 'e'), hash_with_work(2, 'f'));
+        let local = chain_with_hashes(&[&genesis, &twelve, &twelve]);
+        let remote = chain_with_hashes(&[&genesis, &other_twelve, &other_twelve]);
+        let longer = chain_with_hashes(&[&genesis, &eight, &eight, &eight]);
+        assert_eq!(ProofOfWork.select_chain(local.clone(), longer).len(), 4);
+        assert_eq!(ProofOfWork.select_chain(local, remote)[1].hash, twelve);
+    }
+}
@@ -0,0 +1,117 @@
+//! Read-only HTTP endpoint for scripts and dashboards (`--http <addr>`).
+//!
+//! The chain lives inside the swarm's behaviour and is only touched from the
+//! event loop, so the server task does not read it directly: each request is
+//! passed to the loop as an `HttpQuery` and answered through its `reply` channel.
+//!
+//! Routes (GET only, JSON bodies): `/chain`, `/block/{id}`, `/balance/{address}`,
+//! `/mempool`, `/stats`.
+//!
+//! With `--hmac-secret` every response carries `X-Signature: sha256=<hex>`, the
+//! HMAC-SHA256 of its body under that secret.
+
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::{mpsc, oneshot},
+};
+use crate::key::hmac_sha256;
+use crate::logging;
+
+/* Longest request line read; anything longer is refused */
+pub const MAX_REQUEST_LINE: usize = 2048;
+
+/* A GET for `path`, waiting for (status, JSON body) from the event loop */
+#[derive(Debug)]
+pub struct HttpQuery {
+    pub path: String,
+    pub reply: oneshot::Sender<(u16, String)>,
+}
+
+/* Binds `addr` and serves connections on their own tasks; queries go to `sender`.
+   Responses are signed with `secret` when there is one. */
+pub fn spawn_server(addr: String, secret: Option<String>, sender: mpsc::UnboundedSender<HttpQuery>) {
+    spawn(async move {
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(target: logging::NET, "can't serve HTTP on {}: {}", addr, e);
+                return;
+            }
+        };
+        info!(target: logging::NET, "serving HTTP on {}", addr);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    spawn(serve(stream, secret.clone(), sender.clone()));
+                }
+                Err(e) => warn!(target: logging::NET, "can't accept HTTP connection: {}", e),
+            }
+        }
+    });
+}
+
+/* One request per connection: the request line is all that is looked at */
+async fn serve(stream: TcpStream, secret: Option<String>, sender: mpsc::UnboundedSender<HttpQuery>) {
+    // one byte over the limit is enough to tell an oversized line
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_LINE as u64 + 1);
+    let mut line = String::new();
+    let (status, body) = match reader.read_line(&mut line).await {
+        Ok(_) if line.len() > MAX_REQUEST_LINE => (414, error_body("request line too long")),
+        Ok(_) => match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["GET", path, _version] => query(path, &sender).await,
+            [_, _, _] => (405, error_body("only GET is supported")),
+            _ => (400, error_body("malformed request line")),
+        },
+        Err(e) => {
+            warn!(target: logging::NET, "can't read HTTP request: {}", e);
+            return;
+        }
+    };
+    let signature = match &secret {
+        Some(secret) => format!("X-Signature: sha256={}\r\n", hmac_sha256(secret.as_bytes(), body.as_bytes())),
+        None => String::new(),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        signature,
+        body
+    );
+    let mut stream = reader.into_inner().into_inner();
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        warn!(target: logging::NET, "can't write HTTP response: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn query(path: &str, sender: &mpsc::UnboundedSender<HttpQuery>) -> (u16, String) {
+    let (reply, answer) = oneshot::channel();
+    let query = HttpQuery { path: path.to_string(), reply };
+    if sender.send(query).is_err() {
+        return (503, error_body("node is shutting down"));
+    }
+    answer
+        .await
+        .unwrap_or_else(|_| (503, error_body("node is shutting down")))
+}
+
+pub fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        414 => "URI Too Long",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
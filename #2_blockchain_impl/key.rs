@@ -5,8 +5,37 @@ use secp256k1::rand::rngs::OsRng;
 use secp256k1::{All, Message, PublicKey, Secp256k1, SecretKey, Signature};
 use sha2::{Digest, Sha256};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use secp256k1::rand::thread_rng;
 
+/* Longest vanity prefix accepted; every extra hex character means 16x the work */
+pub const MAX_VANITY_PREFIX_LEN: usize = 4;
+/* A short address is this many hex characters of sha256(public key)... */
+pub const ADDRESS_HASH_LEN: usize = 32;
+/* ...followed by this many of sha256 over those, so a mistyped address is caught */
+pub const ADDRESS_CHECKSUM_LEN: usize = 8;
+/* SHA-256 block size, which HMAC pads its key to */
+const HMAC_BLOCK_LEN: usize = 64;
+
+/* Why a key or signature given as hex couldn't be used */
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum KeyError {
+    InvalidSecretKey,
+    InvalidPublicKey,
+    InvalidSignature,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::InvalidSecretKey => write!(f, "invalid secret key"),
+            KeyError::InvalidPublicKey => write!(f, "invalid public key"),
+            KeyError::InvalidSignature => write!(f, "invalid signature"),
+        }
+    }
+}
+
 pub struct KeyMaster {
     pub secp: Secp256k1<All>,
     pub public_key: String,
@@ -20,66 +49,81 @@ impl KeyMaster {
         let mut rng = OsRng::new().expect("OsRng");
         let secret_key = SecretKey::new(&mut rng);
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
-        return KeyMaster {
-            secp: secp,
+        KeyMaster {
+            secp,
             secret_key: secret_key.to_string(),
             public_key: public_key.to_string(),
+        }
+    }
+
+    /* Deterministic keypair for `seed`. The derivation is fixed and must stay so,
+       or existing seeds would stop giving back their keys: the secret key is
+       sha256(seed); in the (~2^-128) case that isn't a valid secp256k1 secret
+       key, sha256 is applied again to the previous digest until it is. */
+    pub fn from_seed(seed: &[u8]) -> Result<KeyMaster, KeyError> {
+        // every empty seed would land on the same, publicly known key
+        if seed.is_empty() {
+            return Err(KeyError::InvalidSecretKey);
+        }
+        let secp = Secp256k1::new();
+        let mut digest = Sha256::digest(seed);
+        let secret_key = loop {
+            match SecretKey::from_slice(&digest) {
+                Ok(secret_key) => break secret_key,
+                Err(_) => digest = Sha256::digest(&digest),
+            }
         };
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        Ok(KeyMaster {
+            secp,
+            secret_key: secret_key.to_string(),
+            public_key: public_key.to_string(),
+        })
     }
 
     /* To start it from already generated values */
-    pub fn holding_these(secret_key: &str, public_key: &str) -> KeyMaster {
+    #[allow(dead_code)]
+    pub fn holding_these(secret_key: &str, public_key: &str) -> Result<KeyMaster, KeyError> {
         let secp = Secp256k1::new();
-        let secret_key = SecretKey::from_str(secret_key).unwrap();
-        let public_key = PublicKey::from_str(public_key).unwrap();
-        return KeyMaster {
-            secp: secp,
+        let secret_key = SecretKey::from_str(secret_key).map_err(|_| KeyError::InvalidSecretKey)?;
+        let public_key = PublicKey::from_str(public_key).map_err(|_| KeyError::InvalidPublicKey)?;
+        Ok(KeyMaster {
+            secp,
             secret_key: secret_key.to_string(),
             public_key: public_key.to_string(),
-        };
+        })
+    }
+
+    /* Short form of public_key, see address_from_public_key */
+    pub fn address(&self) -> String {
+        address_from_public_key(&self.public_key)
     }
 
     /* Sign a message */
-    pub fn sign(&self, message: String) -> String {
+    pub fn sign(&self, message: String) -> Result<String, KeyError> {
         let message_ = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
-        return self
-            .secp
-            .sign(
-                &message_,
-                &SecretKey::from_str(&self.secret_key[..]).unwrap(),
-            )
-            .to_string();
+        let secret_key = SecretKey::from_str(&self.secret_key[..]).map_err(|_| KeyError::InvalidSecretKey)?;
+        Ok(self.secp.sign(&message_, &secret_key).to_string())
     }
 
-    /* Verify a message */
-    pub fn verify(&self, message: String, signature: String) -> bool {
-        let message_ = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
-        return self
-            .secp
-            .verify(
-                &message_,
-                &Signature::from_str(&signature[..]).unwrap(),
-                &PublicKey::from_str(&self.public_key[..]).unwrap(),
-            )
-            .is_ok();
+    /* Verify a message; Ok(false) is a well-formed signature that doesn't match */
+    #[allow(dead_code)]
+    pub fn verify(&self, message: String, signature: String) -> Result<bool, KeyError> {
+        self.verify_with_public_key(self.public_key.clone(), message, signature)
     }
 
     /* Verify a message using another public key */
+    #[allow(dead_code)]
     pub fn verify_with_public_key(
         &self,
         public_key: String,
         message: String,
         signature: String,
-    ) -> bool {
+    ) -> Result<bool, KeyError> {
         let message_ = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
-        return self
-            .secp
-            .verify(
-                &message_,
-                &Signature::from_str(&signature[..]).unwrap(),
-                &PublicKey::from_str(&public_key[..]).unwrap(),
-            )
-            .is_ok();
+        let signature = Signature::from_str(&signature[..]).map_err(|_| KeyError::InvalidSignature)?;
+        let public_key = PublicKey::from_str(&public_key[..]).map_err(|_| KeyError::InvalidPublicKey)?;
+        Ok(self.secp.verify(&message_, &signature, &public_key).is_ok())
     }
 }
 
@@ -101,12 +145,143 @@ pub fn generate_key_pair() -> (String, String) {
     (secret_key_hex, public_key_hex)
 }
 
+/* Generates key pairs until the short address (address_from_public_key) starts
+   with `prefix`. Returns (secret, public, attempts), or None once `cancel` is set. */
+pub fn find_vanity_key(prefix: &str, cancel: &AtomicBool) -> Option<(String, String, u64)> {
+    let mut attempts = 0;
+    while !cancel.load(Ordering::Relaxed) {
+        attempts += 1;
+        let (secret_key, public_key) = generate_key_pair();
+        if address_from_public_key(&public_key).starts_with(prefix) {
+            return Some((secret_key, public_key, attempts));
+        }
+    }
+    None
+}
+
+/* Verify a signature against a hex public key; malformed keys or signatures
+   (they arrive from the network) fail the check instead of panicking */
+pub fn verify_signature(public_key: &str, message: &str, signature: &str) -> bool {
+    let (public_key, signature) = match (PublicKey::from_str(public_key), Signature::from_str(signature)) {
+        (Ok(public_key), Ok(signature)) => (public_key, signature),
+        _ => return false,
+    };
+    let message_ = Message::from_hashed_data::<sha256::Hash>(message.as_bytes());
+    Secp256k1::verification_only()
+        .verify(&message_, &signature, &public_key)
+        .is_ok()
+}
+
+/* Short, checksummed stand-in for a hex public key. It can't be turned back into
+   the key, so transactions keep carrying full keys (signatures are checked
+   against them); the short form is for people to read and type. */
+pub fn address_from_public_key(public_key: &str) -> String {
+    let hash = &hash_string(public_key)[..ADDRESS_HASH_LEN];
+    format!("{}{}", hash, address_checksum(hash))
+}
+
+fn address_checksum(hash: &str) -> String {
+    hash_string(hash)[..ADDRESS_CHECKSUM_LEN].to_string()
+}
+
+/* Looks like a short address: the right length, hex */
+pub fn is_short_address(address: &str) -> bool {
+    address.len() == ADDRESS_HASH_LEN + ADDRESS_CHECKSUM_LEN && address.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/* A short address whose checksum matches */
+pub fn is_valid_short_address(address: &str) -> bool {
+    is_short_address(address) && {
+        let (hash, checksum) = address.split_at(ADDRESS_HASH_LEN);
+        address_checksum(hash) == checksum
+    }
+}
+
+/* HMAC-SHA256 (RFC 2104) of `message` under `key`, hex-encoded. Lets the
+   receiver of something the node sends check it came from a holder of the
+   shared secret. */
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> String {
+    // keys longer than a block are hashed first, shorter ones zero-padded
+    let mut block = [0u8; HMAC_BLOCK_LEN];
+    if key.len() > HMAC_BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    hex::encode(outer.finalize())
+}
 
 /* sha256 */
 pub fn hash_string(in_str: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(in_str);
-    return format!("{:x}", hasher.finalize());
+    format!("{:x}", hasher.finalize())
 }
 
  
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_char_vanity_prefix_is_found() {
+        let cancel = AtomicBool::new(false);
+        let (secret_key, public_key, attempts) = find_vanity_key("a", &cancel).unwrap();
+        assert!(attempts >= 1);
+        assert!(address_from_public_key(&public_key).starts_with('a'));
+        // the pair belongs together
+        let secret_key = SecretKey::from_str(&secret_key).unwrap();
+        assert_eq!(PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).to_string(), public_key);
+    }
+
+    #[test]
+    fn cancelled_vanity_search_gives_up() {
+        let cancel = AtomicBool::new(true);
+        assert!(find_vanity_key("ffff", &cancel).is_none());
+    }
+
+    #[test]
+    fn one_char_typo_fails_the_checksum() {
+        let address = KeyMaster::new().address();
+        assert!(is_valid_short_address(&address));
+        for i in 0..address.len() {
+            let mut typo = address.clone().into_bytes();
+            typo[i] = if typo[i] == b'0' { b'1' } else { b'0' };
+            let typo = String::from_utf8(typo).unwrap();
+            assert!(is_short_address(&typo));
+            assert!(!is_valid_short_address(&typo), "typo at {} went unnoticed", i);
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_key() {
+        let first = KeyMaster::from_seed(b"correct horse battery staple").unwrap();
+        let second = KeyMaster::from_seed(b"correct horse battery staple").unwrap();
+        assert_eq!(first.public_key, second.public_key);
+        assert_eq!(first.secret_key, second.secret_key);
+
+        let other = KeyMaster::from_seed(b"correct horse battery stapler").unwrap();
+        assert_ne!(first.public_key, other.public_key);
+        assert_eq!(KeyMaster::from_seed(b"").err(), Some(KeyError::InvalidSecretKey));
+    }
+
+    // RFC 4231 test cases 2 and 6
+    #[test]
+    fn hmac_matches_reference_vectors() {
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
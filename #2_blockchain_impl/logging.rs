@@ -0,0 +1,116 @@
+//! Log targets for the node's subsystems.
+//!
+//! Log calls pass one of these as `target:` instead of relying on the module
+//! path, so output can be narrowed per subsystem:
+//!
+//! `RUST_LOG=waytoblockchain::consensus=debug`
+//!
+//! All targets share the crate prefix, so `RUST_LOG=waytoblockchain=info`
+//! still enables everything.
+//!
+//! Warnings a peer can trigger at will (invalid blocks) go through
+//! `warn_limited!`, which collapses repeats into a periodic count.
+
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/* libp2p transport, gossipsub, mdns and peer commands */
+pub const NET: &str = "waytoblockchain::net";
+/* block and chain validation, chain selection */
+pub const CONSENSUS: &str = "waytoblockchain::consensus";
+/* pending transaction pool */
+pub const MEMPOOL: &str = "waytoblockchain::mempool";
+/* proof-of-work search */
+pub const MINER: &str = "waytoblockchain::miner";
+
+/* Repeats of a rate-limited warning within this window are only counted */
+pub const LOG_SUPPRESS_WINDOW_SECS: u64 = 10;
+
+pub static LIMITER: Lazy<Mutex<RateLimiter>> =
+    Lazy::new(|| Mutex::new(RateLimiter::new(Duration::from_secs(LOG_SUPPRESS_WINDOW_SECS))));
+
+/* Lets the first event per key through each window and counts the rest */
+pub struct RateLimiter {
+    window: Duration,
+    // key -> (start of its current window, events suppressed in it)
+    state: HashMap<String, (Instant, u64)>,
+}
+
+impl RateLimiter {
+    pub fn new(window: Duration) -> Self {
+        Self { window, state: HashMap::new() }
+    }
+
+    /* Some(n) if this event should be logged, n being how many were suppressed
+       since the last one that was; None if it falls in the current window */
+    pub fn check(&mut self, key: &str, now: Instant) -> Option<u64> {
+        match self.state.get_mut(key) {
+            Some((start, suppressed)) if now.duration_since(*start) < self.window => {
+                *suppressed += 1;
+                None
+            }
+            Some((start, suppressed)) => {
+                let count = *suppressed;
+                *start = now;
+                *suppressed = 0;
+                Some(count)
+            }
+            None => {
+                self.state.insert(key.to_string(), (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+/* warn! that logs a given call site (plus optional key, e.g. the peer) at most
+   once per LOG_SUPPRESS_WINDOW_SECS, reporting how many repeats it swallowed */
+#[macro_export]
+macro_rules! warn_limited {
+    (key: $key:expr, target: $target:expr, $($arg:tt)+) => {{
+        let key = format!("{}:{}:{}", file!(), line!(), $key);
+        let allowed = $crate::logging::LIMITER
+            .lock()
+            .expect("log limiter lock")
+            .check(&key, std::time::Instant::now());
+        if let Some(suppressed) = allowed {
+            if suppressed > 0 {
+                log::warn!(
+                    target: $target,
+                    "suppressed {} repeats of the following warning in the last {}s",
+                    suppressed,
+                    $crate::logging::LOG_SUPPRESS_WINDOW_SECS
+                );
+            }
+            log::warn!(target: $target, $($arg)+);
+        }
+    }};
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::warn_limited!(key: "", target: $target, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_collapsed_into_one_summary() {
+        let window = Duration::from_secs(LOG_SUPPRESS_WINDOW_SECS);
+        let mut limiter = RateLimiter::new(window);
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("invalid block", start), Some(0));
+        for i in 1..=500 {
+            assert_eq!(limiter.check("invalid block", start + Duration::from_millis(i)), None);
+        }
+        // other keys are counted separately
+        assert_eq!(limiter.check("other", start), Some(0));
+
+        assert_eq!(limiter.check("invalid block", start + window), Some(500));
+        assert_eq!(limiter.check("invalid block", start + window), None);
+        assert_eq!(limiter.check("invalid block", start + window * 2), Some(1));
+    }
+}
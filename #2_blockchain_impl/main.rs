@@ -1,41 +1,73 @@
-use chrono::prelude::*;
 use libp2p::{
     core::upgrade,
     futures::StreamExt,
     mplex,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
+    swarm::{Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    Transport,
+    Multiaddr, Transport,
 };
 use log::{error, info, warn};
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use once_cell::sync::Lazy;
+use std::fmt::Debug;
 use std::time::Duration;
 use tokio::{
-    io::{stdin, AsyncBufReadExt, BufReader},
-    select, spawn,
+    select, signal, spawn,
     sync::mpsc,
-    time::sleep,
+    time::{interval, sleep},
 };
 
-const DIFFICULTY_PREFIX: &str = "00";
+/* How often the mempool is checked for local transactions due for rebroadcast */
+const REBROADCAST_CHECK_SECS: u64 = 10;
+/* How often the mempool is checked for expired transactions (see `--mempool-ttl-secs`) */
+const MEMPOOL_PURGE_CHECK_SECS: u64 = 60;
+/* How often the network watchdog looks for a stuck transport (see `--watchdog-secs`) */
+const WATCHDOG_CHECK_SECS: u64 = 5;
 
+/* What to do after a listener went away */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListenerAction {
+    /* other listeners are still up */
+    Continue,
+    /* nothing listens any more: try once more on config::DEFAULT_LISTEN_ADDR */
+    Retry,
+    /* the fallback failed too */
+    Exit,
+}
+
+fn listener_action(active_listeners: usize, fallback_tried: bool) -> ListenerAction {
+    if active_listeners > 0 {
+        ListenerAction::Continue
+    } else if !fallback_tried {
+        ListenerAction::Retry
+    } else {
+        ListenerAction::Exit
+    }
+}
+
+mod logging;
+mod config;
+mod repl;
+mod output;
+mod http;
 mod peer;
 mod key;
 mod transaction;
-use transaction::Transaction;
 mod mempool;
+mod merkle;
 mod block;
+mod consensus;
 use block::*;
 use crate::blockchain::*;
 
 mod blockchain;
+mod wal;
 
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
+    Lazy::force(&config::CONFIG);
     ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
     /*
         * Здесь настраивается транспорт для обмена данными между узлами. Используется TCP для обеспечения соединения между узлами.
@@ -44,47 +76,39 @@ async fn main() {
      */
 
 
-    info!("Peer Id: {}", peer::PEER_ID.clone());
+    info!(target: logging::NET, "Peer Id: {}", peer::PEER_ID.clone());
     let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
     let (init_sender, mut init_rcv) = mpsc::unbounded_channel();
 
-    let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&peer::KEYS)
-        .expect("can create auth keys");
-
-    let transp = TokioTcpConfig::new()
-        .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
-
-    let behaviour = peer::AppBehaviour::new(Blockchain::new(), response_sender, init_sender.clone()).await;
-
-    let mut swarm = SwarmBuilder::new(transp, behaviour, *peer::PEER_ID)
-        .executor(Box::new(|fut| {
-            spawn(fut);
-        }))
-        .build();
+    let blockchain = load_blockchain(&config::CONFIG);
+    let behaviour = peer::AppBehaviour::new(blockchain, response_sender.clone()).await;
+    let mut swarm = build_swarm(behaviour);
     //////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-    let mut stdin = BufReader::new(stdin()).lines();
-
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-        .expect("swarm can be started");
+    let (input_sender, mut input_rcv) = mpsc::unbounded_channel();
+    repl::spawn_input(input_sender);
+    let (http_sender, mut http_rcv) = mpsc::unbounded_channel();
+    if let Some(addr) = &config::CONFIG.http {
+        http::spawn_server(addr.clone(), config::CONFIG.hmac_secret.clone(), http_sender);
+    }
+
+    let mut active_listeners = 0;
+    let mut fallback_tried = false;
+    if listen(&mut swarm, &config::CONFIG.listen) {
+        active_listeners += 1;
+    }
+    recover_listening(&mut swarm, &mut active_listeners, &mut fallback_tried);
+    peer::dial_bootstrap_peers(&mut swarm);
     ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
     /*
     Здесь создается и настраивается экземпляр Swarm, который представляет собой множество узлов,
     способных обмениваться сообщениями между собой. Swarm является основной структурой для P2P взаимодействия.
      */
-    spawn(async move {
-        sleep(Duration::from_secs(1)).await;
-        info!("sending init event");
-        init_sender.send(true).expect("can send init event");
-    });
+    send_init_later(init_sender.clone(), true);
+    let mut rebroadcast_timer = interval(Duration::from_secs(REBROADCAST_CHECK_SECS));
+    let mut watchdog_timer = interval(Duration::from_secs(WATCHDOG_CHECK_SECS));
+    let mut purge_timer = interval(Duration::from_secs(MEMPOOL_PURGE_CHECK_SECS));
+    // the chain moves with the behaviour when the swarm is rebuilt, so this stays subscribed
+    let mut reorgs = swarm.behaviour().app.subscribe_reorgs();
     ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
     loop {
         /*
@@ -92,26 +116,59 @@ async fn main() {
         Он использует макрос select! из библиотеки tokio, который позволяет асинхронно обрабатывать
         несколько потенциальных источников событий. В данном случае обрабатываются следующие типы событий:
 
-         * Ввод пользователя с клавиатуры (stdin.next_line()).
+         * Ввод пользователя с клавиатуры (input_rcv.recv(), см. repl::spawn_input).
+         * Сигнал Ctrl-C (signal::ctrl_c()), после которого узел сохраняет состояние и завершается.
+         * Запросы HTTP-интерфейса (http_rcv.recv(), см. http::spawn_server).
          * Получение ответа от другого узла (response_rcv.recv()).
          * Получение инициализационного события (init_rcv.recv()).
+         * Таймер повторной рассылки транзакций из мемпула (rebroadcast_timer.tick()).
+         * Таймер сторожа сети (watchdog_timer.tick()).
+         * Таймер очистки мемпула от устаревших транзакций (purge_timer.tick()).
+         * Реорганизация цепочки (reorgs.recv(), см. Blockchain::subscribe_reorgs).
          * События от Swarm (swarm.select_next_some()).
          */
+        let mut listener_closed = false;
+        let mut connected_peer = None;
         let evt = {
             select! {
-                line = stdin.next_line() => Some(peer::EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
+                Some(line) = input_rcv.recv() => Some(peer::EventType::Input(line)),
+                _ = signal::ctrl_c() => Some(peer::EventType::Shutdown),
+                Some(query) = http_rcv.recv() => Some(peer::EventType::Http(query)),
                 response = response_rcv.recv() => {
                     Some(peer::EventType::LocalChainResponse(response.expect("response exists")))
                 },
-                _init = init_rcv.recv() => {
-                    Some(peer::EventType::Init)
+                init = init_rcv.recv() => {
+                    match init {
+                        Some(false) => Some(peer::EventType::Resync),
+                        _ => Some(peer::EventType::Init),
+                    }
+                }
+                _tick = rebroadcast_timer.tick() => {
+                    Some(peer::EventType::Rebroadcast)
+                }
+                _tick = watchdog_timer.tick() => {
+                    Some(peer::EventType::WatchdogCheck)
                 }
+                _tick = purge_timer.tick() => {
+                    Some(peer::EventType::PurgeMempool)
+                }
+                Ok(reorg) = reorgs.recv() => Some(peer::EventType::Reorg(reorg)),
                 event = swarm.select_next_some() => {
-                    info!("Unhandled Swarm Event: {:?}", event);
+                    if let SwarmEvent::ConnectionEstablished { peer_id, .. } = &event {
+                        connected_peer = Some(*peer_id);
+                    }
+                    listener_closed = log_swarm_event(event);
                     None
                 },
             }
         };
+        if listener_closed {
+            active_listeners = active_listeners.saturating_sub(1);
+            recover_listening(&mut swarm, &mut active_listeners, &mut fallback_tried);
+        }
+        if let Some(peer) = connected_peer {
+            peer::handle_connection_established(&mut swarm, peer);
+        }
         ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
         if let Some(event) = evt {
             /*
@@ -123,46 +180,279 @@ async fn main() {
             Внутри каждого варианта события (peer::EventType::Init, peer::EventType::LocalChainResponse, peer::EventType::Input) выполняются соответствующие действия в зависимости от типа события. Например:
 
              * Если тип события - инициализация (peer::EventType::Init), то выполняется блок кода для инициализации узла, отправки запроса цепи блоков другому узлу и т.д.
-             * Если тип события - ответ от локальной цепи блоков (peer::EventType::LocalChainResponse), то этот ответ публикуется в сеть через протокол floodsub.
+             * Если тип события - ответ от локальной цепи блоков (peer::EventType::LocalChainResponse), то этот ответ публикуется в сеть через протокол gossipsub.
              * Если тип события - ввод пользователя (peer::EventType::Input), то выполняются различные команды, такие как вывод списка узлов сети, вывод цепи блоков или создание нового блока.
              */
             match event {
                 peer::EventType::Init => {
-                    let peers = peer::get_list_peers(&swarm);
-                    swarm.behaviour_mut().app.genesis();
-
-                    info!("connected nodes: {}", peers.len());
-                    if !peers.is_empty() {
-                        let req = peer::LocalChainRequest {
-                            from_peer_id: peers
-                                .iter()
-                                .last()
-                                .expect("at least one peer")
-                                .to_string(),
-                        };
-
-                        let json = serde_json::to_string(&req).expect("can jsonify request");
-                        swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .publish(peer::CHAIN_TOPIC.clone(), json.as_bytes());
+                    let app = &mut swarm.behaviour_mut().app;
+                    if app.blocks.is_empty() {
+                        app.genesis();
+                    }
+                    let replayed = app.replay_wal();
+                    if replayed > 0 {
+                        info!(target: logging::CONSENSUS, "replayed {} blocks from the WAL", replayed);
+                    }
+                    // folds whatever the WAL held into the chain file
+                    app.persist();
+                    // after the WAL, so transactions it confirmed aren't restored as pending
+                    let mempool_file = &config::CONFIG.mempool_file;
+                    if !config::CONFIG.in_memory && mempool_file.exists() {
+                        match app.restore_mempool(mempool_file) {
+                            Ok(restored) => info!(target: logging::MEMPOOL, "restored {} pending transactions from {}", restored, mempool_file.display()),
+                            Err(e) => error!(target: logging::MEMPOOL, "ignoring stored mempool {}: {}", mempool_file.display(), e),
+                        }
                     }
+                    peer::request_chain_from_peers(&mut swarm);
+                }
+                peer::EventType::Rebroadcast => peer::handle_rebroadcast(&mut swarm),
+                peer::EventType::PurgeMempool => peer::handle_purge_mempool(&mut swarm),
+                peer::EventType::Reorg(reorg) => peer::handle_reorg(reorg),
+                peer::EventType::Http(query) => peer::handle_http_query(query, &swarm),
+                peer::EventType::Resync => peer::request_chain_from_peers(&mut swarm),
+                peer::EventType::WatchdogCheck => {
+                    if peer::handle_watchdog_check(&mut swarm) {
+                        swarm = rebuild_swarm(swarm, response_sender.clone()).await;
+                        active_listeners = 0;
+                        fallback_tried = false;
+                        if listen(&mut swarm, &config::CONFIG.listen) {
+                            active_listeners += 1;
+                        }
+                        recover_listening(&mut swarm, &mut active_listeners, &mut fallback_tried);
+                        peer::dial_bootstrap_peers(&mut swarm);
+                        send_init_later(init_sender.clone(), false);
+                    }
+                }
+                peer::EventType::Shutdown => {
+                    peer::handle_shutdown(&mut swarm);
+                    break;
                 }
                 peer::EventType::LocalChainResponse(resp) => {
                     let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(peer::CHAIN_TOPIC.clone(), json.as_bytes());
+                    swarm.behaviour_mut().publish(&peer::CHAIN_TOPIC, json);
                 }
                 peer::EventType::Input(line) => match line.as_str() {
+                    "quit" => {
+                        peer::handle_shutdown(&mut swarm);
+                        break;
+                    }
                     "ls p" => peer::handle_print_peers(&swarm),
+                    "whoami" => peer::handle_whoami(&swarm),
+                    "audit" => peer::handle_audit(&swarm),
+                    "repair" => peer::handle_repair(&mut swarm),
+                    "stats" => peer::handle_stats(&swarm),
+                    "supply" => peer::handle_supply(&swarm),
+                    "finality" => peer::handle_finality(&swarm),
+                    "mempool graph" => peer::handle_mempool_graph(&swarm),
+                    cmd if cmd.starts_with("prioritize") || cmd.starts_with("deprioritize") => {
+                        peer::handle_prioritize(cmd, &mut swarm)
+                    }
+                    cmd if cmd.starts_with("balance") => peer::handle_balance(cmd, &swarm),
+                    cmd if cmd.starts_with("ledger") => peer::handle_ledger(cmd, &swarm),
+                    cmd if cmd.starts_with("vanity") => peer::handle_vanity(cmd, &mut swarm),
+                    cmd if cmd.starts_with("hashrate") => peer::handle_hashrate(cmd),
+                    cmd if cmd.starts_with("get block") => peer::handle_get_block(cmd, &mut swarm),
+                    cmd if cmd.starts_with("get tx") => peer::handle_get_tx(cmd, &swarm),
+                    cmd if cmd.starts_with("gentx") => peer::handle_gentx(cmd, &mut swarm),
+                    cmd if cmd.starts_with("diff peer") => peer::handle_diff_peer(cmd, &mut swarm),
+                    cmd if cmd.starts_with("accept") => peer::handle_accept(cmd, &mut swarm),
+                    cmd if cmd.starts_with("partition") => peer::handle_partition(cmd, &mut swarm),
                     cmd if cmd.starts_with("ls c") => peer::handle_print_chain(&swarm),
+                    cmd if cmd.starts_with("verify transaction") => peer::handle_verify_transaction(cmd, &swarm),
+                    cmd if cmd.starts_with("restore-backup") => peer::handle_restore_backup(cmd, &mut swarm),
+                    cmd if cmd.starts_with("lookup") => peer::handle_lookup(cmd, &swarm),
+                    cmd if cmd.starts_with("path") => peer::handle_merkle_path(cmd, &swarm),
+                    cmd if cmd.starts_with("replaytx") => peer::handle_replaytx(cmd, &swarm),
+                    cmd if cmd.starts_with("calchash") => peer::handle_calchash(cmd),
+                    cmd if cmd.starts_with("signbytes") => peer::handle_signbytes(cmd),
+                    cmd if cmd.starts_with("export csv") => peer::handle_export_csv(cmd, &swarm),
                     cmd if cmd.starts_with("create b") => peer::handle_create_block(cmd, &mut swarm),
-                    cmd if cmd.starts_with("send") => peer::handle_add_transaction(cmd,&swarm),
-                    _ => error!("unknown command"),
+                    cmd if cmd.starts_with("send") => peer::handle_add_transaction(cmd, &mut swarm),
+                    _ => error!(target: logging::NET, "unknown command"),
                 },
             }
         }
     }
 }
+
+/* The transport described at the top of main, and the swarm on it */
+fn build_swarm(behaviour: peer::AppBehaviour) -> Swarm<peer::AppBehaviour> {
+    let auth_keys = Keypair::<X25519Spec>::new()
+        .into_authentic(&peer::KEYS)
+        .expect("can create auth keys");
+
+    let transp = TokioTcpConfig::new()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+        .multiplex(mplex::MplexConfig::new())
+        .boxed();
+
+    SwarmBuilder::new(transp, behaviour, *peer::PEER_ID)
+        .executor(Box::new(|fut| {
+            spawn(fut);
+        }))
+        .build()
+}
+
+/* Replaces a swarm the watchdog found stuck with a new one on a fresh transport.
+   The chain and the rest of the node's state (see AppBehaviour::inherit) move
+   over; connections, listeners and gossip state are dropped with the old swarm. */
+async fn rebuild_swarm(
+    mut old: Swarm<peer::AppBehaviour>,
+    response_sender: mpsc::UnboundedSender<peer::ChainResponse>,
+) -> Swarm<peer::AppBehaviour> {
+    let app = std::mem::replace(&mut old.behaviour_mut().app, Blockchain::new());
+    let mut behaviour = peer::AppBehaviour::new(app, response_sender).await;
+    behaviour.inherit(old.behaviour_mut());
+    drop(old);
+    build_swarm(behaviour)
+}
+
+/* Gives the swarm a second to connect before the init event (true) or, after a
+   rebuild, the resync (false) asks peers for their chain */
+fn send_init_later(init_sender: mpsc::UnboundedSender<bool>, first_start: bool) {
+    spawn(async move {
+        sleep(Duration::from_secs(1)).await;
+        info!(target: logging::NET, "sending init event");
+        init_sender.send(first_start).expect("can send init event");
+    });
+}
+
+/* Starts a listener on `addr`; false (after logging why) if that isn't possible */
+fn listen(swarm: &mut Swarm<peer::AppBehaviour>, addr: &str) -> bool {
+    let addr: Multiaddr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!(target: logging::NET, "invalid listen address {}: {}", addr, e);
+            return false;
+        }
+    };
+    match Swarm::listen_on(swarm, addr.clone()) {
+        Ok(_) => true,
+        Err(e) => {
+            error!(target: logging::NET, "can't listen on {}: {}", addr, e);
+            false
+        }
+    }
+}
+
+/* The chain as `config` sets it up: consensus parameters and limits, and unless
+   in-memory, the WAL, the stored chain and where it is saved. No genesis yet,
+   that waits for the startup sync. */
+fn load_blockchain(config: &config::NodeConfig) -> Blockchain {
+    let mut blockchain = Blockchain::new();
+    blockchain.genesis_config = config.genesis.clone();
+    blockchain.difficulty = config.difficulty;
+    blockchain.mempool.limits = mempool::MempoolLimits {
+        max_ancestors: config.max_ancestors,
+        max_descendants: config.max_descendants,
+        max_size: config.max_mempool,
+    };
+    if config.in_memory {
+        // no chain file and no WAL: persist() and record_block never touch the disk
+        info!(target: logging::CONSENSUS, "in-memory mode: the chain is not loaded from or saved to disk");
+    } else {
+        if let Some(path) = &config.wal {
+            blockchain.wal = Some(wal::Wal::open(path).expect("can open the write-ahead log"));
+        }
+        let chain_file = config.chain_file.clone();
+        if chain_file.exists() {
+            match blockchain.load_blocks(&chain_file) {
+                Ok(()) => info!(target: logging::CONSENSUS, "loaded {} blocks from {}", blockchain.blocks.len(), chain_file.display()),
+                Err(e) => error!(target: logging::CONSENSUS, "ignoring stored chain {}: {}", chain_file.display(), e),
+            }
+        }
+        blockchain.chain_file = Some(chain_file);
+        blockchain.backups = config.backups;
+    }
+    blockchain
+}
+
+/* Falls back to the default address once nothing listens; exits if even that fails */
+fn recover_listening(swarm: &mut Swarm<peer::AppBehaviour>, active_listeners: &mut usize, fallback_tried: &mut bool) {
+    loop {
+        match listener_action(*active_listeners, *fallback_tried) {
+            ListenerAction::Continue => return,
+            ListenerAction::Retry => {
+                *fallback_tried = true;
+                warn!(target: logging::NET, "no listener left, retrying on {}", config::DEFAULT_LISTEN_ADDR);
+                if listen(swarm, config::DEFAULT_LISTEN_ADDR) {
+                    *active_listeners += 1;
+                }
+            }
+            ListenerAction::Exit => {
+                error!(target: logging::NET, "can't listen on any address, shutting down");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/* Logs a swarm event; true if it was a listener closing */
+fn log_swarm_event<TEvent: Debug, TErr: Debug>(event: SwarmEvent<TEvent, TErr>) -> bool {
+    match event {
+        SwarmEvent::NewListenAddr { address, .. } => {
+            info!(target: logging::NET, "listening on {}", address);
+            false
+        }
+        SwarmEvent::ListenerError { listener_id, error } => {
+            error!(target: logging::NET, "listener {:?} failed: {}", listener_id, error);
+            false
+        }
+        SwarmEvent::ListenerClosed { listener_id, addresses, reason } => {
+            match reason {
+                Ok(()) => warn!(target: logging::NET, "listener {:?} on {:?} closed", listener_id, addresses),
+                Err(e) => error!(target: logging::NET, "listener {:?} on {:?} closed: {}", listener_id, addresses, e),
+            }
+            true
+        }
+        event => {
+            info!(target: logging::NET, "Unhandled Swarm Event: {:?}", event);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_failure_retries_once_then_exits() {
+        assert_eq!(listener_action(1, false), ListenerAction::Continue);
+        assert_eq!(listener_action(2, true), ListenerAction::Continue);
+        assert_eq!(listener_action(0, false), ListenerAction::Retry);
+        assert_eq!(listener_action(0, true), ListenerAction::Exit);
+    }
+
+    #[test]
+    fn in_memory_node_writes_nothing() {
+        use crate::blockchain::tests::{mine, temp_dir, transfer};
+        use crate::key::KeyMaster;
+        let dir = temp_dir("in_memory");
+        let chain_file = dir.join("chain.json").display().to_string();
+        let wal = dir.join("chain.wal").display().to_string();
+        let config = |extra: &[&str]| {
+            let args = ["--difficulty", "8", "--backups", "2", "--chain-file", chain_file.as_str(), "--wal", wal.as_str()];
+            config::NodeConfig::parse(args.iter().chain(extra).map(|arg| arg.to_string()))
+        };
+        let run = |chain: &mut Blockchain| {
+            chain.genesis();
+            let key = KeyMaster::new();
+            mine(chain, &key, vec![]);
+            let tx = transfer(chain, &key, 1.0, 0.0);
+            mine(chain, &key, vec![tx]);
+            chain.persist();
+        };
+
+        let mut chain = load_blockchain(&config(&["--in-memory"]));
+        run(&mut chain);
+        assert_eq!(chain.blocks.len(), 3);
+        assert_eq!(chain.audit(), Ok(()));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // the same run on disk does leave files
+        run(&mut load_blockchain(&config(&[])));
+        assert!(std::path::Path::new(&chain_file).exists());
+    }
+}
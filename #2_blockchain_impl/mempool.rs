@@ -1,7 +1,432 @@
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use crate::{transaction::Transaction};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::transaction::{Transaction, TxRejectReason};
+
+/* First rebroadcast retry delay; doubles on every attempt up to the max */
+pub const REBROADCAST_BASE_SECS: i64 = 30;
+pub const REBROADCAST_MAX_SECS: i64 = 30 * 60;
+/* Defaults for MempoolLimits */
+pub const DEFAULT_MAX_ANCESTORS: usize = 25;
+pub const DEFAULT_MAX_DESCENDANTS: usize = 25;
+pub const DEFAULT_MAX_SIZE: usize = 5000;
+/* Age past which an unmined transaction is purged, see `--mempool-ttl-secs` */
+pub const DEFAULT_MAX_TX_AGE_SECS: i64 = 24 * 60 * 60;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Mempool {
-    transactions: Vec<Transaction>
+    transactions: Vec<Transaction>,
+    /* txid -> rebroadcast schedule, only for transactions created on this node */
+    #[serde(skip)]
+    local: HashMap<String, RebroadcastState>,
+    #[serde(skip)]
+    pub limits: MempoolLimits,
+    /* txids the operator marked with `prioritize`; mined ahead of fee order */
+    #[serde(skip)]
+    prioritized: HashSet<String>,
+}
+
+/* Bounds on chains of pending transactions. A pending transaction depends on
+   another if it spends from that one's receiver; ancestors and descendants are
+   the transitive closure of that within the mempool. */
+#[derive(Debug, Clone)]
+pub struct MempoolLimits {
+    pub max_ancestors: usize,
+    pub max_descendants: usize,
+    /* pending transactions held at most; past it the lowest fee makes way */
+    pub max_size: usize,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        Self {
+            max_ancestors: DEFAULT_MAX_ANCESTORS,
+            max_descendants: DEFAULT_MAX_DESCENDANTS,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RebroadcastState {
+    last_broadcast: Option<i64>,
+    attempts: u32,
+}
+
+impl RebroadcastState {
+    fn is_due(&self, now: i64) -> bool {
+        match self.last_broadcast {
+            None => true,
+            Some(last) => {
+                let delay = (REBROADCAST_BASE_SECS << self.attempts.saturating_sub(1).min(16))
+                    .min(REBROADCAST_MAX_SECS);
+                now - last >= delay
+            }
+        }
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self {
+            transactions: vec![],
+            local: HashMap::new(),
+            limits: MempoolLimits::default(),
+            prioritized: HashSet::new(),
+        }
+    }
+
+    /* Returns whether `tx` was kept: false if a transaction with the same id is
+       already pending, if it would break the dependency limits (see
+       check_dependency_limits), or if the pool is full and `tx` doesn't pay
+       more than the cheapest pending transaction, which is evicted otherwise */
+    pub fn add_transaction(&mut self, tx: Transaction) -> bool {
+        let id = tx.id();
+        if self.transactions.iter().any(|pending| pending.id() == id) {
+            return false;
+        }
+        if self.check_dependency_limits(&tx).is_err() {
+            return false;
+        }
+        if self.transactions.len() >= self.limits.max_size {
+            let cheapest = self
+                .transactions
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.fee.partial_cmp(&b.fee).unwrap_or(std::cmp::Ordering::Equal))
+                .map(|(index, pending)| (index, pending.fee));
+            match cheapest {
+                Some((index, fee)) if tx.fee > fee => {
+                    let evicted = self.transactions.remove(index).id();
+                    self.local.remove(&evicted);
+                    self.prioritized.remove(&evicted);
+                }
+                _ => return false,
+            }
+        }
+        self.transactions.push(tx);
+        true
+    }
+
+    /* Like add_transaction, but the transaction originated here and will be
+       rebroadcast until it leaves the mempool */
+    pub fn add_local_transaction(&mut self, tx: Transaction) -> bool {
+        let id = tx.id();
+        if !self.add_transaction(tx) {
+            return false;
+        }
+        self.local.insert(id, RebroadcastState { last_broadcast: None, attempts: 0 });
+        true
+    }
+
+    /* Local transactions whose backoff has elapsed at `now`; marks them as broadcast */
+    pub fn due_for_rebroadcast(&mut self, now: i64) -> Vec<Transaction> {
+        let transactions = &self.transactions;
+        self.local.retain(|id, _| transactions.iter().any(|tx| &tx.id() == id));
+        let mut due = vec![];
+        for tx in &self.transactions {
+            if let Some(state) = self.local.get_mut(&tx.id()) {
+                if state.is_due(now) {
+                    state.last_broadcast = Some(now);
+                    state.attempts += 1;
+                    due.push(tx.clone());
+                }
+            }
+        }
+        due
+    }
+
+    /* Whether `tx` can join without it, or any pending ancestor of it, ending up
+       with more unconfirmed relatives than the limits allow */
+    pub fn check_dependency_limits(&self, tx: &Transaction) -> Result<(), TxRejectReason> {
+        let ancestors = self.ancestors(tx);
+        if ancestors.len() > self.limits.max_ancestors {
+            return Err(TxRejectReason::TooManyAncestors);
+        }
+        if self.descendants(tx).len() > self.limits.max_descendants {
+            return Err(TxRejectReason::TooManyDescendants);
+        }
+        // tx becomes one more descendant of each of its ancestors
+        for ancestor in ancestors {
+            if self.descendants(ancestor).len() + 1 > self.limits.max_descendants {
+                return Err(TxRejectReason::TooManyDescendants);
+            }
+        }
+        Ok(())
+    }
+
+    /* Adjacency listing: every pending txid with the pending txids it spends from directly */
+    pub fn dependency_graph(&self) -> Vec<(String, Vec<String>)> {
+        self.transactions
+            .iter()
+            .map(|tx| {
+                let id = tx.id();
+                let parents = self
+                    .transactions
+                    .iter()
+                    .filter(|pending| pending.receiver == tx.sender && pending.id() != id)
+                    .map(Transaction::id)
+                    .collect();
+                (id, parents)
+            })
+            .collect()
+    }
+
+    /* Pending transactions `tx` spends from, directly or through other pending ones */
+    fn ancestors(&self, tx: &Transaction) -> Vec<&Transaction> {
+        self.walk(tx, |from, pending| pending.receiver == from.sender)
+    }
+
+    /* Pending transactions that spend from `tx`, directly or through other pending ones */
+    fn descendants(&self, tx: &Transaction) -> Vec<&Transaction> {
+        self.walk(tx, |from, pending| pending.sender == from.receiver)
+    }
+
+    fn walk(&self, tx: &Transaction, linked: impl Fn(&Transaction, &Transaction) -> bool) -> Vec<&Transaction> {
+        let start = tx.id();
+        let mut seen = HashSet::new();
+        let mut found = vec![];
+        let mut frontier = vec![tx];
+        while let Some(current) = frontier.pop() {
+            for pending in &self.transactions {
+                let id = pending.id();
+                if id != start && linked(current, pending) && seen.insert(id) {
+                    found.push(pending);
+                    frontier.push(pending);
+                }
+            }
+        }
+        found
+    }
+
+    /* Drops transactions that made it into a block, matched by id */
+    pub fn remove_transactions(&mut self, txs: &[Transaction]) {
+        let confirmed: Vec<String> = txs.iter().map(Transaction::id).collect();
+        self.transactions.retain(|tx| !confirmed.contains(&tx.id()));
+        self.local.retain(|id, _| !confirmed.contains(id));
+        self.prioritized.retain(|id| !confirmed.contains(id));
+    }
+
+    /* Keeps only the pending transactions `keep` accepts; returns how many were dropped */
+    pub fn retain(&mut self, keep: impl Fn(&Transaction) -> bool) -> usize {
+        let before = self.transactions.len();
+        self.transactions.retain(|tx| keep(tx));
+        let transactions = &self.transactions;
+        self.local.retain(|id, _| transactions.iter().any(|tx| &tx.id() == id));
+        self.prioritized.retain(|id| transactions.iter().any(|tx| &tx.id() == id));
+        before - self.transactions.len()
+    }
+
+    /* Drops transactions created more than `max_age_secs` ago; returns how many */
+    pub fn purge_expired(&mut self, max_age_secs: i64) -> usize {
+        let cutoff = Utc::now().timestamp() - max_age_secs;
+        self.retain(|tx| tx.created_at >= cutoff)
+    }
+
+    /* Marks a pending transaction for the next block this node mines regardless
+       of its fee; false if no such transaction is pending */
+    pub fn prioritize(&mut self, id: &str) -> bool {
+        if self.get(id).is_none() {
+            return false;
+        }
+        self.prioritized.insert(id.to_string());
+        true
+    }
+
+    /* Undoes prioritize; false if the transaction wasn't prioritized */
+    pub fn deprioritize(&mut self, id: &str) -> bool {
+        self.prioritized.remove(id)
+    }
+
+    pub fn is_prioritized(&self, id: &str) -> bool {
+        self.prioritized.contains(id)
+    }
+
+    /* Up to `max_count` pending transactions in the order a miner should take
+       them: prioritized ones first, then by fee, highest first; ties keep
+       arrival order */
+    pub fn select_for_block(&self, max_count: usize) -> Vec<Transaction> {
+        let mut selected: Vec<&Transaction> = self.transactions.iter().collect();
+        selected.sort_by(|a, b| {
+            let a_prioritized = self.prioritized.contains(&a.id());
+            let b_prioritized = self.prioritized.contains(&b.id());
+            b_prioritized
+                .cmp(&a_prioritized)
+                .then(b.fee.partial_cmp(&a.fee).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        selected.into_iter().take(max_count).cloned().collect()
+    }
+
+    /* Pending transactions as JSON, written to a temporary file and renamed over
+       `path` like the chain file */
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec(&self.transactions)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /* Transactions written by save_to_file; they still have to be checked against
+       the chain before they go back into a mempool */
+    pub fn load_from_file(path: &Path) -> io::Result<Vec<Transaction>> {
+        let json = fs::read(path)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.get(&tx.id()).is_some()
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Transaction> {
+        self.transactions.iter().find(|tx| tx.id() == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Unsigned is fine here: the mempool leaves validation to the chain */
+    fn pending(sender: &str, receiver: &str, fee: f64) -> Transaction {
+        Transaction::new(sender.to_string(), receiver.to_string(), 1.0, fee)
+    }
+
+    #[test]
+    fn rebroadcast_backs_off_exponentially() {
+        let mut mempool = Mempool::new();
+        let local = pending("alice", "bob", 0.1);
+        assert!(mempool.add_local_transaction(local.clone()));
+        // heard from a peer: someone else's to rebroadcast
+        assert!(mempool.add_transaction(pending("carol", "dave", 0.1)));
+
+        let start = 1_000_000;
+        let ids = |due: Vec<Transaction>| due.iter().map(Transaction::id).collect::<Vec<_>>();
+        assert_eq!(ids(mempool.due_for_rebroadcast(start)), vec![local.id()]);
+        assert!(mempool.due_for_rebroadcast(start + REBROADCAST_BASE_SECS - 1).is_empty());
+
+        let second = start + REBROADCAST_BASE_SECS;
+        assert_eq!(ids(mempool.due_for_rebroadcast(second)), vec![local.id()]);
+        // the wait doubles
+        assert!(mempool.due_for_rebroadcast(second + 2 * REBROADCAST_BASE_SECS - 1).is_empty());
+        let third = second + 2 * REBROADCAST_BASE_SECS;
+        assert_eq!(ids(mempool.due_for_rebroadcast(third)), vec![local.id()]);
+
+        // and is capped
+        let mut now = third;
+        for _ in 0..20 {
+            now += REBROADCAST_MAX_SECS;
+            assert_eq!(mempool.due_for_rebroadcast(now).len(), 1);
+        }
+
+        mempool.remove_transactions(&[local]);
+        assert!(mempool.due_for_rebroadcast(now + REBROADCAST_MAX_SECS).is_empty());
+    }
+
+    #[test]
+    fn dependency_chain_is_capped_at_max_ancestors() {
+        let mut mempool = Mempool::new();
+        mempool.limits.max_ancestors = 2;
+        assert!(mempool.add_transaction(pending("a", "b", 0.0)));
+        assert!(mempool.add_transaction(pending("b", "c", 0.0)));
+        // two pending ancestors: at the limit
+        assert!(mempool.add_transaction(pending("c", "d", 0.0)));
+
+        let too_deep = pending("d", "e", 0.0);
+        assert_eq!(mempool.check_dependency_limits(&too_deep), Err(TxRejectReason::TooManyAncestors));
+        assert!(!mempool.add_transaction(too_deep));
+        assert_eq!(mempool.len(), 3);
+
+        // unrelated senders aren't affected
+        assert!(mempool.add_transaction(pending("x", "y", 0.0)));
+    }
+
+    #[test]
+    fn dependency_graph_links_spender_to_parent() {
+        let mut mempool = Mempool::new();
+        let parent = pending("alice", "bob", 0.0);
+        let child = pending("bob", "carol", 0.0);
+        mempool.add_transaction(parent.clone());
+        mempool.add_transaction(child.clone());
+
+        let graph = mempool.dependency_graph();
+        assert_eq!(graph, vec![(parent.id(), vec![]), (child.id(), vec![parent.id()])]);
+    }
+
+    #[test]
+    fn prioritized_transaction_goes_first() {
+        let mut mempool = Mempool::new();
+        let cheap = pending("alice", "bob", 0.01);
+        let rich = pending("carol", "dave", 5.0);
+        let richer = pending("erin", "frank", 9.0);
+        for tx in [&cheap, &rich, &richer] {
+            mempool.add_transaction(tx.clone());
+        }
+        let order = |mempool: &Mempool| mempool.select_for_block(3).iter().map(Transaction::id).collect::<Vec<_>>();
+        assert_eq!(order(&mempool), vec![richer.id(), rich.id(), cheap.id()]);
+
+        assert!(mempool.prioritize(&cheap.id()));
+        assert_eq!(order(&mempool), vec![cheap.id(), richer.id(), rich.id()]);
+        assert_eq!(mempool.select_for_block(1)[0].id(), cheap.id());
+
+        assert!(mempool.deprioritize(&cheap.id()));
+        assert_eq!(order(&mempool), vec![richer.id(), rich.id(), cheap.id()]);
+        assert!(!mempool.prioritize("unknown"));
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_cheapest() {
+        let mut mempool = Mempool::new();
+        mempool.limits.max_size = 3;
+        let cheapest = pending("a", "b", 0.1);
+        for tx in [pending("c", "d", 0.5), cheapest.clone(), pending("e", "f", 0.3)] {
+            assert!(mempool.add_transaction(tx));
+        }
+        assert_eq!(mempool.len(), 3);
+
+        // paying more than the cheapest takes its place
+        let better = pending("g", "h", 0.2);
+        assert!(mempool.add_transaction(better.clone()));
+        assert_eq!(mempool.len(), 3);
+        assert!(!mempool.contains(&cheapest));
+        assert!(mempool.contains(&better));
+
+        // not paying more than the cheapest left is refused
+        let too_cheap = pending("i", "j", 0.2);
+        assert!(!mempool.add_transaction(too_cheap.clone()));
+        assert!(!mempool.contains(&too_cheap));
+        assert_eq!(mempool.len(), 3);
+    }
+
+    #[test]
+    fn purge_expired_drops_old_transactions_only() {
+        let mut mempool = Mempool::new();
+        let mut old = pending("alice", "bob", 0.1);
+        old.created_at = Utc::now().timestamp() - 3600;
+        let fresh = pending("carol", "dave", 0.1);
+        assert!(mempool.add_transaction(old.clone()));
+        assert!(mempool.add_transaction(fresh.clone()));
+
+        assert_eq!(mempool.purge_expired(600), 1);
+        assert!(!mempool.contains(&old));
+        assert!(mempool.contains(&fresh));
+    }
 }
@@ -0,0 +1,182 @@
+use sha2::{Digest, Sha256};
+
+/* Domain separation prefixes: a leaf can never be passed off as an internal node or vice versa */
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Debug, Clone)]
+struct MerkleNode {
+    hash: String,
+    left: Option<Box<MerkleNode>>,
+    right: Option<Box<MerkleNode>>,
+}
+
+impl MerkleNode {
+    fn new(hash: String, left: Option<Box<MerkleNode>>, right: Option<Box<MerkleNode>>) -> MerkleNode {
+        MerkleNode { hash, left, right }
+    }
+
+    // sha256(0x00 || data)
+    fn hash_leaf(data: &str) -> String {
+        MerkleNode::prefixed_hash(LEAF_PREFIX, &[data])
+    }
+
+    // sha256(0x01 || left || right)
+    fn hash_internal(left: &str, right: &str) -> String {
+        MerkleNode::prefixed_hash(NODE_PREFIX, &[left, right])
+    }
+
+    fn prefixed_hash(prefix: u8, parts: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([prefix]);
+        for part in parts {
+            hasher.update(part.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/* Binary hash tree over a list of items. Each leaf is the hash of one item; an
+   odd node at the end of a level is paired with itself. Leaves and internal
+   nodes are hashed with different prefixes (LEAF_PREFIX, NODE_PREFIX). */
+#[derive(Debug)]
+pub struct MerkleTree {
+    root: Option<Box<MerkleNode>>,
+}
+
+impl MerkleTree {
+    /* No items gives an empty tree with no root */
+    pub fn new<S: AsRef<str>>(data: &[S]) -> MerkleTree {
+        if data.is_empty() {
+            return MerkleTree { root: None };
+        }
+        let nodes = data
+            .iter()
+            .map(|d| MerkleNode::new(MerkleNode::hash_leaf(d.as_ref()), None, None))
+            .collect::<Vec<_>>();
+        MerkleTree { root: Some(Box::new(MerkleTree::build_tree(nodes))) }
+    }
+
+    fn build_tree(mut nodes: Vec<MerkleNode>) -> MerkleNode {
+        if nodes.len() == 1 {
+            return nodes.remove(0);
+        }
+
+        let mut parents = Vec::new();
+        for i in (0..nodes.len()).step_by(2) {
+            let left = nodes[i].clone();
+            let right = if i + 1 < nodes.len() {
+                nodes[i + 1].clone()
+            } else {
+                left.clone()
+            };
+
+            let hash = MerkleNode::hash_internal(&left.hash, &right.hash);
+            let parent = MerkleNode::new(hash, Some(Box::new(left)), Some(Box::new(right)));
+            parents.push(parent);
+        }
+
+        MerkleTree::build_tree(parents)
+    }
+
+    pub fn root_hash(&self) -> Option<String> {
+        self.root.as_ref().map(|node| node.hash.clone())
+    }
+
+    /* Sibling hashes from the leaf up to the root, each flagged true when the
+       sibling sits on the left. None if no leaf has this hash. */
+    pub fn generate_proof(&self, leaf_hash: &str) -> Option<Vec<(String, bool)>> {
+        let mut proof = Vec::new();
+        if MerkleTree::collect_proof(self.root.as_deref()?, leaf_hash, &mut proof) {
+            Some(proof)
+        } else {
+            None
+        }
+    }
+
+    // depth-first search for the leaf; siblings are pushed while unwinding, so leaf first
+    fn collect_proof(node: &MerkleNode, leaf_hash: &str, proof: &mut Vec<(String, bool)>) -> bool {
+        match (&node.left, &node.right) {
+            (Some(left), Some(right)) => {
+                if MerkleTree::collect_proof(left, leaf_hash, proof) {
+                    proof.push((right.hash.clone(), false));
+                    true
+                } else if MerkleTree::collect_proof(right, leaf_hash, proof) {
+                    proof.push((left.hash.clone(), true));
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => node.hash == leaf_hash,
+        }
+    }
+}
+
+/* Hash of one item as it appears as a leaf */
+pub fn leaf_hash(data: &str) -> String {
+    MerkleNode::hash_leaf(data)
+}
+
+/* Rebuilds the path from `leaf_hash` with the proof's siblings and compares it to `root` */
+pub fn verify_proof(leaf_hash: &str, proof: &[(String, bool)], root: &str) -> bool {
+    let mut hash = leaf_hash.to_string();
+    for (sibling, sibling_is_left) in proof {
+        hash = if *sibling_is_left {
+            MerkleNode::hash_internal(sibling, &hash)
+        } else {
+            MerkleNode::hash_internal(&hash, sibling)
+        };
+    }
+    hash == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("tx{}", i)).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_for_odd_and_even_counts() {
+        for count in 1..=7 {
+            let data = items(count);
+            let tree = MerkleTree::new(&data);
+            let root = tree.root_hash().unwrap();
+            for item in &data {
+                let leaf = leaf_hash(item);
+                let proof = tree.generate_proof(&leaf).unwrap();
+                assert!(verify_proof(&leaf, &proof, &root), "{} of {} leaves", item, count);
+            }
+        }
+    }
+
+    #[test]
+    fn no_proof_for_absent_leaf() {
+        let tree = MerkleTree::new(&items(5));
+        assert!(tree.generate_proof(&leaf_hash("tx5")).is_none());
+        assert!(MerkleTree::new::<String>(&[]).generate_proof(&leaf_hash("tx0")).is_none());
+    }
+
+    #[test]
+    fn proof_fails_against_other_root() {
+        let tree = MerkleTree::new(&items(4));
+        let leaf = leaf_hash("tx2");
+        let proof = tree.generate_proof(&leaf).unwrap();
+        let other_root = MerkleTree::new(&items(5)).root_hash().unwrap();
+        assert!(!verify_proof(&leaf, &proof, &other_root));
+    }
+
+    #[test]
+    fn internal_node_is_not_accepted_as_leaf() {
+        let tree = MerkleTree::new(&items(2));
+        let root = tree.root_hash().unwrap();
+        // the root's preimage, presented as the data of a single leaf
+        let crafted = format!("{}{}", leaf_hash("tx0"), leaf_hash("tx1"));
+
+        assert!(!verify_proof(&leaf_hash(&crafted), &[], &root));
+        assert_ne!(MerkleTree::new(&[crafted.as_str()]).root_hash().unwrap(), root);
+    }
+}
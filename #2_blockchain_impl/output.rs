@@ -0,0 +1,123 @@
+use serde::Serialize;
+use crate::block::Block;
+use crate::config::CONFIG;
+use crate::transaction::Transaction;
+
+/* Command results go through here: with `--json-output` they are printed to stdout
+   as one JSON line (kept apart from the log), otherwise `human` logs them as text */
+pub fn emit<T: Serialize>(result: &T, human: impl FnOnce(&T)) {
+    if CONFIG.json_output {
+        println!("{}", serde_json::to_string(result).expect("can jsonify command output"));
+    } else {
+        human(result);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhoAmI {
+    pub peer_id: String,
+    /* short address of the node's wallet */
+    pub address: String,
+    pub chain_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Supply {
+    pub issued: f64,
+    pub burned: f64,
+    pub circulating: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finality {
+    pub depth: u64,
+    pub height: usize,
+    pub safe_tip: Option<u64>,
+    pub non_final: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hashrate {
+    pub attempts: u64,
+    pub seconds: f64,
+    pub hashes_per_second: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MempoolGraph {
+    pub transactions: Vec<MempoolNode>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MempoolNode {
+    pub txid: String,
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Balance {
+    pub address: String,
+    pub balance: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LedgerStats {
+    pub accounts: usize,
+    pub total_value: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LedgerDust {
+    pub threshold: f64,
+    pub accounts: Vec<Balance>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VanityKey {
+    pub address: String,
+    pub public_key: String,
+    pub secret_key: String,
+    pub attempts: u64,
+}
+
+/* `path <txid>`: the steps from a transaction's leaf to its block's merkle root */
+#[derive(Debug, Serialize)]
+pub struct MerklePath {
+    pub txid: String,
+    pub block_id: u64,
+    pub leaf: String,
+    pub steps: Vec<MerkleStep>,
+    pub merkle_root: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MerkleStep {
+    pub sibling: String,
+    /* "left" or "right": where the sibling sits when the pair is hashed */
+    pub side: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Lookup {
+    Block { block: Block },
+    Transaction { block_id: u64, transaction: Transaction },
+    NotFound { hash: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::test_chain;
+
+    #[test]
+    fn lookup_json_is_tagged_with_its_kind() {
+        let chain = test_chain();
+        let found: serde_json::Value = serde_json::to_value(Lookup::Block { block: chain.blocks[0].clone() }).unwrap();
+        assert_eq!(found["kind"], "block");
+        assert_eq!(found["block"]["hash"], chain.blocks[0].hash.as_str());
+
+        let missing: serde_json::Value = serde_json::to_value(Lookup::NotFound { hash: "ab".to_string() }).unwrap();
+        assert_eq!(missing, serde_json::json!({ "kind": "not_found", "hash": "ab" }));
+    }
+}
@@ -4,7 +4,7 @@
 //!
 //! ## Обзор
 //!
-//! P2P сеть состоит из узлов, которые обмениваются сообщениями между собой с использованием протокола floodsub для распространения сообщений и mDNS для обнаружения узлов.
+//! P2P сеть состоит из узлов, которые обмениваются сообщениями между собой с использованием протокола gossipsub для распространения сообщений и mDNS для обнаружения узлов.
 //!
 //! ## Модули
 //!
@@ -14,15 +14,54 @@
 //!
 //! - `ChainResponse`: Структура, представляющая ответ на запрос цепочки блоков.
 //! - `LocalChainRequest`: Структура, представляющая запрос на получение локальной цепочки блоков.
+//! - `BlockRequest` / `BlockResponse`: Запрос одного блока по номеру и ответ на него.
 //! - `EventType`: Перечисление, определяющее типы событий, которые могут возникнуть в приложении.
-//! - `AppBehaviour`: Поведение сетевого узла приложения, включающее floodsub и mDNS.
+//! - `AppBehaviour`: Поведение сетевого узла приложения, включающее gossipsub и mDNS.
 //!
 //! ## Функции
 //!
-//! - `get_list_peers`: Получает список узлов в сети.
+//! - `load_or_create_peer_key`: Загружает ключ узла из файла (или создаёт его), чтобы PeerId не менялся между перезапусками.
+//! - `load_wallet`: Создаёт кошелёк узла из фразы `--wallet-seed` (или случайный), чтобы награды за блоки шли на один адрес между перезапусками.
+//! - `get_list_peers`: Получает список подключённых узлов (найденных через mDNS, `--dial` или подключившихся к нам).
 //! - `handle_print_peers`: Выводит список узлов в лог.
 //! - `handle_print_chain`: Выводит локальную цепочку блоков в лог.
-//! - `handle_create_block`: Создает новый блок и транслирует его в сеть.
+//! - `request_chain_from_peers`: Запрашивает цепочку блоков у одного из узлов сети.
+//! - `handle_watchdog_check`: Рассылает heartbeat и сообщает, что Swarm нужно пересоздать, если при подключённых узлах долго нет сообщений.
+//! - `handle_get_block`: Запрашивает у узла один блок по номеру.
+//! - `handle_partition`: Включает/выключает имитацию сетевого разделения (для тестирования, только с флагом `--dev`).
+//! - `handle_rebroadcast`: Повторно рассылает собственные неподтверждённые транзакции.
+//! - `handle_http_query`: Отвечает на запрос HTTP-интерфейса (цепочка, блок, баланс, мемпул, статистика).
+//! - `handle_purge_mempool`: Удаляет из мемпула транзакции старше `--mempool-ttl-secs`.
+//! - `handle_reorg`: Выводит блоки, выпавшие из цепочки при реорганизации.
+//! - `handle_accept`: Приостанавливает/возобновляет приём новых блоков.
+//! - `handle_diff_peer`: Запрашивает цепочку узла и выводит отличия от локальной.
+//! - `handle_balance`: Выводит баланс адреса (по умолчанию кошелька узла) по подтверждённым транзакциям.
+//! - `handle_ledger`: Выводит число счетов с ненулевым балансом и их сумму, либо счета с «пылью».
+//! - `handle_vanity`: Ищет в отдельном потоке ключ, адрес которого начинается с заданного префикса.
+//! - `handle_whoami`: Выводит идентификатор узла и идентификатор сети (chain id).
+//! - `handle_verify_transaction`: Проверяет транзакцию и выводит результат каждой проверки.
+//! - `handle_mempool_graph`: Выводит зависимости между транзакциями в мемпуле.
+//! - `handle_prioritize`: Ставит транзакцию из мемпула в начало очереди майнинга (или убирает её оттуда).
+//! - `handle_gentx`: Генерирует тестовые транзакции в мемпул (только с флагом `--dev`).
+//! - `handle_stats`: Выводит высоту цепочки, число транзакций, размер мемпула, сложность, пропускную способность и оценку хешрейта.
+//! - `dial_bootstrap_peers`: Подключается к адресам из `--dial` (для сетей, где не работает mDNS).
+//! - `handle_connection_established`: Учитывает подключившийся узел или блокирует его, если его нет в allow-list.
+//! - `handle_shutdown`: Сохраняет цепочку и мемпул перед остановкой узла.
+//! - `handle_repair`: Обрезает цепочку до валидного префикса и заново синхронизируется.
+//! - `handle_supply`: Выводит выпущенные, сожжённые и находящиеся в обращении монеты.
+//! - `handle_finality`: Показывает, какие блоки считаются окончательными, и безопасную вершину.
+//! - `handle_hashrate`: Измеряет скорость майнинга узла (хешей в секунду), не создавая блок.
+//! - `handle_audit`: Проверяет всю локальную цепочку, включая генезис-блок.
+//! - `handle_lookup`: Ищет блок по хешу или транзакцию по txid.
+//! - `handle_get_tx`: Ищет подтверждённую транзакцию по txid и выводит высоту её блока.
+//! - `handle_merkle_path`: Выводит путь Меркла от транзакции до корня её блока.
+//! - `handle_replaytx`: Повторно проверяет транзакцию на состоянии цепочки до её блока.
+//! - `handle_calchash`: Вычисляет хеш блока по заданным полям заголовка.
+//! - `handle_signbytes`: Выводит байты транзакции, которые подписываются (в hex).
+//! - `handle_restore_backup`: Показывает резервные копии цепочки или восстанавливает выбранную.
+//! - `handle_export_csv`: Экспортирует транзакции цепочки в CSV файл.
+//! - `handle_add_transaction`: Подписывает транзакцию ключом узла, добавляет в мемпул и рассылает в сеть.
+//! - `handle_create_block`: Создает новый блок из транзакций мемпула и транслирует его в сеть.
 //!
 //! ## Методы
 //!
@@ -32,121 +71,486 @@
 //!
 //! ### `NetworkBehaviourEventProcess` для `AppBehaviour`
 //!
-//! - `inject_event`: Обрабатывает входящие события floodsub и mDNS.
+//! - `inject_event`: Обрабатывает входящие события gossipsub и mDNS.
 //!
 //! ### `NetworkBehaviourEventProcess` для `MdnsEvent`
 //!
 //! - `inject_event`: Обрабатывает события mDNS, такие как обнаружение и истечение срока узлов.
 
 use super::{Blockchain, Block};
+use crate::blockchain::{ReorgEvent, BALANCE_EPSILON, TPS_WINDOW_BLOCKS};
+use crate::block::{
+    calculate_hash, hashes_per_second, leading_zero_bits, measure_hashrate, merkle_proof, merkle_root, HASHRATE_SAMPLE_SECS,
+};
+use chrono::Utc;
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, Topic},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic as Topic, MessageAuthenticity, ValidationMode,
+    },
     identity,
     mdns::{Mdns, MdnsEvent},
     swarm::{NetworkBehaviourEventProcess, Swarm},
-    NetworkBehaviour, PeerId,
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use crate::transaction::Transaction;
+use crate::transaction::{generate_test_transactions, Transaction, TxRejectReason};
+use crate::key::{address_from_public_key, find_vanity_key, is_short_address, is_valid_short_address, KeyMaster, MAX_VANITY_PREFIX_LEN};
+use crate::logging;
+use crate::warn_limited;
+use crate::config::{NodeConfig, CONFIG};
+use crate::output;
+use crate::http;
 
-pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
+pub static KEYS: Lazy<identity::Keypair> = Lazy::new(|| match &CONFIG.peer_key {
+    Some(path) if !CONFIG.in_memory => load_or_create_peer_key(path),
+    _ => identity::Keypair::generate_ed25519(),
+});
 pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
+
 pub static CHAIN_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("chains"));
 pub static BLOCK_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("blocks"));
+pub static TX_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("transactions"));
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChainResponse {
     pub blocks: Vec<Block>,
     pub receiver: String,
+    pub chain_id: Option<String>,
+    /* the request_id of the LocalChainRequest this answers */
+    #[serde(default)]
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LocalChainRequest {
     pub from_peer_id: String,
+    pub chain_id: Option<String>,
+    /* random, so the answer can be matched to the request */
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl LocalChainRequest {
+    pub fn new(from_peer_id: String, chain_id: Option<String>) -> Self {
+        Self {
+            from_peer_id,
+            chain_id,
+            request_id: Some(format!("{:016x}", rand::random::<u64>())),
+        }
+    }
+}
+
+/* How long `diff peer` waits for the peer's chain; a later reply is treated like
+   any other chain response */
+pub const DIFF_TIMEOUT_SECS: u64 = 30;
+
+/* A `diff peer` request waiting for its answer */
+pub struct PendingDiff {
+    pub peer_id: String,
+    pub request_id: String,
+    pub sent: Instant,
+}
+
+impl PendingDiff {
+    /* Whether `resp` from `source` is the answer, as of `now` */
+    pub fn is_answered_by(&self, resp: &ChainResponse, source: &str, now: Instant) -> bool {
+        now.duration_since(self.sent) < Duration::from_secs(DIFF_TIMEOUT_SECS)
+            && self.peer_id == source
+            && resp.request_id.as_deref() == Some(self.request_id.as_str())
+    }
+}
+
+/* `get block <id>`: asks peer `from_peer_id` for its block at height `id` only */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockRequest {
+    pub id: u64,
+    pub from_peer_id: String,
+}
+
+/* Answer to a BlockRequest; None if the peer's chain isn't that long */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockResponse {
+    pub id: u64,
+    pub block: Option<Block>,
+    pub receiver: String,
+}
+
+/* Published by handle_watchdog_check while peers are connected, so that a quiet
+   but healthy network still shows the watchdog messages coming in */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub heartbeat_from: String,
+    pub sent_at: i64,
 }
 
 pub enum EventType {
     LocalChainResponse(ChainResponse),
     Input(String),
     Init,
+    /* the swarm was rebuilt: ask the peers for their chain again */
+    Resync,
+    Rebroadcast,
+    PurgeMempool,
+    Http(http::HttpQuery),
+    WatchdogCheck,
+    /* choose_chain dropped local blocks (see Blockchain::subscribe_reorgs) */
+    Reorg(ReorgEvent),
+    Shutdown,
 }
 
+/* Decides when the network looks stuck: peers are connected, yet no message,
+   not even a Heartbeat, has come in for `threshold` */
+pub struct Watchdog {
+    pub threshold: Duration,
+    last_exchange: Instant,
+    last_heartbeat: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn new(threshold: Duration) -> Self {
+        Self { threshold, last_exchange: Instant::now(), last_heartbeat: None }
+    }
+
+    pub fn record_exchange(&mut self, now: Instant) {
+        self.last_exchange = now;
+    }
+
+    /* Three heartbeats per threshold, so one lost heartbeat doesn't trip a peer's watchdog */
+    pub fn heartbeat_due(&self, now: Instant) -> bool {
+        !self.threshold.is_zero()
+            && self.last_heartbeat.is_none_or(|sent| now.duration_since(sent) >= self.threshold / 3)
+    }
+
+    pub fn record_heartbeat(&mut self, now: Instant) {
+        self.last_heartbeat = Some(now);
+    }
+
+    /* A zero threshold disables the watchdog; with no peers silence is expected */
+    pub fn should_restart(&self, now: Instant, connected_peers: usize) -> bool {
+        !self.threshold.is_zero()
+            && connected_peers > 0
+            && now.duration_since(self.last_exchange) >= self.threshold
+    }
+}
+
+/* How long a block or transaction payload counts as already handled */
+pub const SEEN_PAYLOAD_SECS: u64 = 60;
+
+/* Digests of recently received payloads. The same block or transaction reaches
+   us once per peer that publishes it, and only the first copy is worth handling;
+   a copy that arrives after `ttl` is handled again, as a rebroadcast may be the
+   first one we can accept. */
+pub struct SeenPayloads {
+    ttl: Duration,
+    seen: HashMap<String, Instant>,
+}
+
+impl SeenPayloads {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, seen: HashMap::new() }
+    }
+
+    /* Whether `data` wasn't seen within the `ttl` before `now`. Repeats don't
+       extend the window, it runs from the first copy. */
+    pub fn first_sighting(&mut self, data: &[u8], now: Instant) -> bool {
+        let ttl = self.ttl;
+        self.seen.retain(|_, at| now.duration_since(*at) < ttl);
+        let digest = hex::encode(Sha256::digest(data));
+        if self.seen.contains_key(&digest) {
+            return false;
+        }
+        self.seen.insert(digest, now);
+        true
+    }
+}
 
 #[derive(NetworkBehaviour)]
 pub struct AppBehaviour {
-    //     floodsub: Это компонент, который реализует протокол floodsub для обмена сообщениями в P2P сети.
-    //          * Floodsub используется для широковещательной передачи сообщений по темам (topics) в сети.
+    //     gossipsub: Это компонент, который реализует протокол gossipsub для обмена сообщениями в P2P сети.
+    //          * Gossipsub рассылает сообщения по темам (topics) через ограниченную сетку узлов, а не всем подряд;
+    //            каждое сообщение (по источнику и порядковому номеру) пересылается узлом не более одного раза,
+    //            а повторно пришедшие блоки и транзакции отсеиваются по хешу содержимого (см. SeenPayloads).
     //     Он позволяет вашему узлу отправлять и принимать сообщения о новых блоках, запросах цепочки блоков и других событиях в сети.
     //          * mdns: Это компонент, который обеспечивает механизм обнаружения узлов в локальной сети с использованием Multicast DNS (mDNS).
     //     Он позволяет вашему узлу обнаруживать другие узлы в локальной сети без необходимости использования централизованных серверов обнаружения.
     //     response_sender: Это отправитель сообщений, который используется для отправки ответов на запросы, связанные с цепочкой блоков.
     //     Например, когда ваш узел получает запрос на получение локальной цепочки блоков от другого узла,
     //     он может использовать этот отправитель, чтобы отправить ответ с текущей локальной цепочкой блоков.
-    //          * init_sender: Это отправитель сообщений, который используется для отправки инициализационных событий.
-    //     Например, при запуске вашего узла он может отправить инициализационное событие для сигнализации другим узлам, что он готов к работе.
     //           * app: Это структура, которая представляет блокчейна. Она содержит логику приложения,
     //     такую как хранение блоков, обработка новых блоков и выбор цепочки блоков. В AppBehaviour она используется для доступа к функциональности приложения из сетевого поведения.
-    pub floodsub: Floodsub,
+    pub gossipsub: Gossipsub,
     pub mdns: Mdns,
     #[behaviour(ignore)]
     pub response_sender: mpsc::UnboundedSender<ChainResponse>,
     #[behaviour(ignore)]
-    pub init_sender: mpsc::UnboundedSender<bool>,
-    #[behaviour(ignore)]
     pub app: Blockchain,
+    #[behaviour(ignore)]
+    pub partitioned: bool,
+    #[behaviour(ignore)]
+    pub accept_paused: bool,
+    /* chain request sent by `diff peer`; its reply is shown, not adopted */
+    #[behaviour(ignore)]
+    pub pending_diff: Option<PendingDiff>,
+    #[behaviour(ignore)]
+    pub wallet: KeyMaster,
+    /* peers we exchange messages with; mDNS reports the same peer repeatedly */
+    #[behaviour(ignore)]
+    pub partial_view: HashSet<PeerId>,
+    /* set to stop the running `vanity` search */
+    #[behaviour(ignore)]
+    pub vanity_cancel: Option<Arc<AtomicBool>>,
+    #[behaviour(ignore)]
+    pub watchdog: Watchdog,
+    #[behaviour(ignore)]
+    pub seen_payloads: SeenPayloads,
 }
 
 impl AppBehaviour {
     pub async fn new(
         app: Blockchain,
         response_sender: mpsc::UnboundedSender<ChainResponse>,
-        init_sender: mpsc::UnboundedSender<bool>,
     ) -> Self {
         let mut behaviour = Self {
             app,
-            floodsub: Floodsub::new(*PEER_ID),
+            gossipsub: Gossipsub::new(MessageAuthenticity::Signed(KEYS.clone()), gossipsub_config())
+                .expect("can create gossipsub"),
             mdns: Mdns::new(Default::default())
                 .await
                 .expect("can create mdns"),
             response_sender,
-            init_sender,
+            partitioned: false,
+            accept_paused: false,
+            pending_diff: None,
+            wallet: load_wallet(&CONFIG),
+            partial_view: HashSet::new(),
+            vanity_cancel: None,
+            watchdog: Watchdog::new(Duration::from_secs(CONFIG.watchdog_secs)),
+            seen_payloads: SeenPayloads::new(Duration::from_secs(SEEN_PAYLOAD_SECS)),
         };
-        behaviour.floodsub.subscribe(CHAIN_TOPIC.clone());
-        behaviour.floodsub.subscribe(BLOCK_TOPIC.clone());
+        for topic in [&*CHAIN_TOPIC, &*BLOCK_TOPIC, &*TX_TOPIC] {
+            behaviour.gossipsub.subscribe(topic).expect("can subscribe to topic");
+        }
 
         behaviour
     }
+
+    /* Gossipsub refuses to publish with no subscribed peer around; not worth more than a note */
+    pub fn publish(&mut self, topic: &Topic, json: String) {
+        if let Err(e) = self.gossipsub.publish(topic.clone(), json.into_bytes()) {
+            info!(target: logging::NET, "not published on {}: {:?}", topic.hash(), e);
+        }
+    }
+
+    /* Gossip every pending transaction so newly joined peers catch up;
+       receivers drop the ones they already have */
+    fn share_mempool(&mut self) {
+        if self.app.mempool.is_empty() {
+            return;
+        }
+        let pending = self.app.mempool.transactions().to_vec();
+        info!(target: logging::MEMPOOL, "sharing {} pending transactions with new peers", pending.len());
+        for tx in pending {
+            let json = serde_json::to_string(&tx).expect("can jsonify transaction");
+            self.publish(&TX_TOPIC, json);
+        }
+    }
+
+    /* Takes over what must survive a swarm rebuild from the behaviour being
+       replaced: the wallet, the operator's toggles and a running vanity search.
+       The chain is handed to `new` directly. */
+    pub fn inherit(&mut self, old: &mut AppBehaviour) {
+        std::mem::swap(&mut self.wallet, &mut old.wallet);
+        self.partitioned = old.partitioned;
+        self.accept_paused = old.accept_paused;
+        self.pending_diff = old.pending_diff.take();
+        self.vanity_cancel = old.vanity_cancel.take();
+    }
+
+    /* mDNS reports a peer once per address and again on every query; only the
+       first report of an allowed peer adds it. Returns whether it was added. */
+    fn add_to_view(&mut self, peer: PeerId) -> bool {
+        if !CONFIG.is_peer_allowed(&peer) || !self.partial_view.insert(peer) {
+            return false;
+        }
+        // explicit peers are dialed by gossipsub and always sent our messages
+        self.gossipsub.add_explicit_peer(&peer);
+        true
+    }
+
+    /* Whether a message from `source` is looked at at all: the sender must be on
+       `config`'s allow-list, if there is one, and the node not partitioned */
+    fn admits(&self, config: &NodeConfig, source: &PeerId) -> bool {
+        if !config.is_peer_allowed(source) {
+            info!(target: logging::NET, "dropping message from peer {} not on the allow-list", source);
+            return false;
+        }
+        if self.partitioned {
+            info!(target: logging::NET, "partitioned: dropping message from {}", source);
+            return false;
+        }
+        true
+    }
+
+    /* Peers that don't know their chain id yet (no genesis) are given the benefit of the doubt */
+    fn same_network(&self, remote_chain_id: &Option<String>) -> bool {
+        match (self.app.chain_id(), remote_chain_id) {
+            (Some(local), Some(remote)) => &local == remote,
+            _ => true,
+        }
+    }
+}
+
+/* Message ids are gossipsub's default, source + sequence number, so every publish
+   goes out: content-hash ids would make gossipsub refuse a repeated chain request,
+   an unchanged chain response or a rebroadcast transaction for as long as the
+   first one sits in its duplicate cache. Payloads are deduplicated on receipt
+   instead, see SeenPayloads. */
+fn gossipsub_config() -> libp2p::gossipsub::GossipsubConfig {
+    GossipsubConfigBuilder::default()
+        .validation_mode(ValidationMode::Strict)
+        .build()
+        .expect("valid gossipsub config")
 }
 
 // incoming event handler
-impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(msg) = event {
+impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        // a peer subscribing is the first moment a publish reaches it; catch it up then
+        if let GossipsubEvent::Subscribed { peer_id, topic } = &event {
+            if *topic == TX_TOPIC.hash() && CONFIG.mempool_sync && CONFIG.is_peer_allowed(peer_id) {
+                self.share_mempool();
+            }
+            return;
+        }
+        if let GossipsubEvent::Message { propagation_source, message: msg, .. } = event {
+            // strict validation makes every message signed, so the source is always set
+            let source = msg.source.unwrap_or(propagation_source);
+            if !self.admits(&CONFIG, &source) {
+                return;
+            }
+            let now = Instant::now();
+            self.watchdog.record_exchange(now);
+            let first_sighting = self.seen_payloads.first_sighting(&msg.data, now);
             if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
                 if resp.receiver == PEER_ID.to_string() {
-                    info!("Response from {}:", msg.source);
-                    resp.blocks.iter().for_each(|r| info!("{:?}", r));
+                    if !self.same_network(&resp.chain_id) {
+                        warn!(target: logging::NET, "ignoring chain from {} on foreign network {:?}", source, resp.chain_id);
+                        return;
+                    }
+                    let is_diff = self
+                        .pending_diff
+                        .as_ref()
+                        .is_some_and(|pending| pending.is_answered_by(&resp, &source.to_string(), now));
+                    if is_diff {
+                        self.pending_diff = None;
+                        let diff = Blockchain::diff_chains(&self.app.blocks, &resp.blocks);
+                        output::emit(&diff, |diff| {
+                            info!(target: logging::NET, "diff with {}:", source);
+                            match diff.common_ancestor {
+                                Some(height) => info!(target: logging::NET, "  common ancestor: block #{}", height),
+                                None => info!(target: logging::NET, "  no common ancestor"),
+                            }
+                            info!(target: logging::NET, "  local only ({}): {:?}", diff.local_only.len(), diff.local_only);
+                            info!(target: logging::NET, "  remote only ({}): {:?}", diff.remote_only.len(), diff.remote_only);
+                        });
+                        return;
+                    }
+                    if self.accept_paused {
+                        info!(target: logging::NET, "block acceptance paused: ignoring chain from {}", source);
+                        return;
+                    }
+                    info!(target: logging::NET, "Response from {}:", source);
+                    resp.blocks.iter().for_each(|r| info!(target: logging::NET, "{:?}", r));
 
-                    self.app.blocks = self.app.choose_chain(self.app.blocks.clone(), resp.blocks);
+                    self.app.adopt_best_chain(resp.blocks);
+                }
+            } else if let Ok(resp) = serde_json::from_slice::<BlockResponse>(&msg.data) {
+                if resp.receiver == PEER_ID.to_string() {
+                    match resp.block {
+                        Some(block) => {
+                            let pretty_json = serde_json::to_string_pretty(&block).expect("can jsonify block");
+                            info!(target: logging::NET, "block #{} from {}: {}", resp.id, source, pretty_json);
+                        }
+                        None => info!(target: logging::NET, "{} has no block #{}", source, resp.id),
+                    }
+                }
+            } else if let Ok(req) = serde_json::from_slice::<BlockRequest>(&msg.data) {
+                // checked before LocalChainRequest, which would also accept this shape
+                if PEER_ID.to_string() == req.from_peer_id {
+                    info!(target: logging::NET, "sending block #{} to {}", req.id, source);
+                    let resp = BlockResponse {
+                        id: req.id,
+                        block: self.app.blocks.get(req.id as usize).cloned(),
+                        receiver: source.to_string(),
+                    };
+                    let json = serde_json::to_string(&resp).expect("can jsonify response");
+                    self.publish(&CHAIN_TOPIC, json);
                 }
             } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                info!("sending local chain to {}", msg.source.to_string());
+                if !self.same_network(&resp.chain_id) {
+                    warn!(target: logging::NET, "ignoring chain request from {} on foreign network {:?}", source, resp.chain_id);
+                    return;
+                }
+                info!(target: logging::NET, "sending local chain to {}", source);
                 let peer_id = resp.from_peer_id;
                 if PEER_ID.to_string() == peer_id {
                     if let Err(e) = self.response_sender.send(ChainResponse {
                         blocks: self.app.blocks.clone(),
-                        receiver: msg.source.to_string(),
+                        receiver: source.to_string(),
+                        chain_id: self.app.chain_id(),
+                        request_id: resp.request_id,
                     }) {
-                        error!("error sending response via channel, {}", e);
+                        error!(target: logging::NET, "error sending response via channel, {}", e);
                     }
                 }
             } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                info!("received new block from {}", msg.source.to_string());
-                self.app.try_add_block(block);
+                if !first_sighting {
+                    return;
+                }
+                if self.accept_paused {
+                    info!(target: logging::NET, "block acceptance paused: dropping block id#{} from {}", block.id, source);
+                    return;
+                }
+                info!(target: logging::NET, "received new block from {}", source);
+                let id = block.id;
+                if let Err(e) = self.app.try_add_block(block) {
+                    warn_limited!(
+                        key: source,
+                        target: logging::NET,
+                        "rejected block id#{} from {}: {}",
+                        id, source, e
+                    );
+                }
+            } else if serde_json::from_slice::<Heartbeat>(&msg.data).is_ok() {
+                // nothing to do: arriving at all was its purpose, see record_exchange above
+            } else if let Ok(tx) = serde_json::from_slice::<Transaction>(&msg.data) {
+                // a rebroadcast of one we already hold isn't worth checking the signature again
+                if !first_sighting || self.app.mempool.contains(&tx) {
+                    return;
+                }
+                if let Err(reason) = self
+                    .app
+                    .validate_transaction(&tx)
+                    .and_then(|_| self.app.mempool.check_dependency_limits(&tx))
+                {
+                    warn!(target: logging::MEMPOOL, "dropping transaction {} from {}: {:?}", tx.id(), source, reason);
+                } else {
+                    let tx_id = tx.id();
+                    if self.app.mempool.add_transaction(tx) {
+                        info!(target: logging::MEMPOOL, "added transaction {} from {} to the mempool", tx_id, source);
+                    }
+                }
             }
         }
     }
@@ -157,13 +561,13 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
         match event {
             MdnsEvent::Discovered(discovered_list) => {
                 for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
+                    self.add_to_view(peer);
                 }
             }
             MdnsEvent::Expired(expired_list) => {
                 for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
+                    if !self.mdns.has_node(&peer) && self.partial_view.remove(&peer) {
+                        self.gossipsub.remove_explicit_peer(&peer);
                     }
                 }
             }
@@ -171,66 +575,1012 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     }
 }
 
-pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
-    info!("Discovered Peers:");
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
+/* The key file holds the raw ed25519 keypair (secret || public, 64 bytes).
+   A missing file is created with a fresh key, so the first run fixes the PeerId.
+   A file that exists but can't be read or decoded stops the node: carrying on
+   with a new identity would silently change the PeerId peers know us by. */
+pub fn load_or_create_peer_key(path: &Path) -> identity::Keypair {
+    match fs::read(path) {
+        Ok(mut bytes) => match identity::ed25519::Keypair::decode(&mut bytes) {
+            Ok(keypair) => identity::Keypair::Ed25519(keypair),
+            Err(e) => refuse_to_start(path, &e),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = identity::ed25519::Keypair::generate();
+            if let Err(e) = write_peer_key(path, &keypair.encode()) {
+                refuse_to_start(path, &e);
+            }
+            info!(target: logging::NET, "created new peer key at {}", path.display());
+            identity::Keypair::Ed25519(keypair)
+        }
+        Err(e) => refuse_to_start(path, &e),
+    }
+}
+
+// the file holds a secret key: readable by the owner only where that can be expressed
+fn write_peer_key(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(bytes)
+}
+
+/* The wallet from `--wallet-seed` if one is given, a fresh random one otherwise */
+pub fn load_wallet(config: &NodeConfig) -> KeyMaster {
+    match &config.wallet_seed {
+        Some(seed) => KeyMaster::from_seed(seed.as_bytes()).expect("--wallet-seed gives a valid key"),
+        None => KeyMaster::new(),
     }
+}
+
+fn refuse_to_start(path: &Path, e: &dyn std::fmt::Display) -> ! {
+    error!(target: logging::NET, "can't use peer key file {}: {}", path.display(), e);
+    std::process::exit(1);
+}
+
+/* Allowed peers gossipsub has a live connection to, however they were found:
+   mDNS only covers the local network, `--dial` peers and inbound ones come in directly */
+pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
+    let unique_peers: HashSet<&PeerId> = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .map(|(peer, _topics)| peer)
+        .filter(|peer| CONFIG.is_peer_allowed(peer))
+        .collect();
     unique_peers.iter().map(|p| p.to_string()).collect()
 }
 
 pub fn handle_print_peers(swarm: &Swarm<AppBehaviour>) {
+    info!(target: logging::NET, "Connected Peers:");
+    let peers = get_list_peers(swarm);
+    peers.iter().for_each(|p| info!(target: logging::NET, "{}", p));
+}
+
+pub fn request_chain_from_peers(swarm: &mut Swarm<AppBehaviour>) {
     let peers = get_list_peers(swarm);
-    peers.iter().for_each(|p| info!("{}", p));
+    info!(target: logging::NET, "connected nodes: {}", peers.len());
+    if !peers.is_empty() {
+        let req = LocalChainRequest::new(
+            peers.iter().last().expect("at least one peer").to_string(),
+            swarm.behaviour().app.chain_id(),
+        );
+
+        let json = serde_json::to_string(&req).expect("can jsonify request");
+        swarm.behaviour_mut().publish(&CHAIN_TOPIC, json);
+    }
 }
 
-pub fn handle_add_transaction(cmd: &str,swarm: &Swarm<AppBehaviour>) {
-    info!("handle_add_transaction()");
+/* Runs on a timer. While peers are connected a Heartbeat goes out now and then,
+   so silence means the transport is broken rather than the network quiet.
+   Returns true once the watchdog finds the network stuck: the caller then
+   rebuilds the swarm, transport included (see `rebuild_swarm` in main). */
+pub fn handle_watchdog_check(swarm: &mut Swarm<AppBehaviour>) -> bool {
+    let now = Instant::now();
+    let connected = swarm.network_info().num_peers();
+    let behaviour = swarm.behaviour_mut();
+    if connected == 0 {
+        // nobody to hear from, so silence doesn't count against the network
+        behaviour.watchdog.record_exchange(now);
+        return false;
+    }
+    if behaviour.watchdog.should_restart(now, connected) {
+        warn!(
+            target: logging::NET,
+            "no messages for {:?} with {} peers connected, rebuilding the swarm",
+            behaviour.watchdog.threshold, connected
+        );
+        return true;
+    }
+    if behaviour.watchdog.heartbeat_due(now) {
+        behaviour.watchdog.record_heartbeat(now);
+        let heartbeat = Heartbeat { heartbeat_from: PEER_ID.to_string(), sent_at: Utc::now().timestamp() };
+        let json = serde_json::to_string(&heartbeat).expect("can jsonify heartbeat");
+        behaviour.publish(&CHAIN_TOPIC, json);
+    }
+    false
+}
+
+/* `get block <id>`: asks one connected peer for a single block instead of its whole chain */
+pub fn handle_get_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    let id = match cmd.strip_prefix("get block").map(str::trim).map(str::parse::<u64>) {
+        Some(Ok(id)) => id,
+        _ => {
+            error!(target: logging::NET, "usage: get block <id>");
+            return;
+        }
+    };
+    let peer = match get_list_peers(swarm).pop() {
+        Some(peer) => peer,
+        None => {
+            error!(target: logging::NET, "no peers to ask for block #{}", id);
+            return;
+        }
+    };
+    info!(target: logging::NET, "requesting block #{} from {}", id, peer);
+    let req = BlockRequest { id, from_peer_id: peer };
+    let json = serde_json::to_string(&req).expect("can jsonify request");
+    swarm.behaviour_mut().publish(&CHAIN_TOPIC, json);
+}
+
+/* Dev command: `partition on` drops every incoming gossipsub message while local
+   mining keeps working, `partition off` rejoins and resyncs through choose_chain */
+pub fn handle_partition(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    if !CONFIG.dev {
+        error!(target: logging::NET, "partition is a dev command, start the node with --dev");
+        return;
+    }
+    match cmd.strip_prefix("partition").map(str::trim) {
+        Some("on") => {
+            swarm.behaviour_mut().partitioned = true;
+            info!(target: logging::NET, "partition on: ignoring incoming messages");
+        }
+        Some("off") => {
+            swarm.behaviour_mut().partitioned = false;
+            info!(target: logging::NET, "partition off: resyncing with peers");
+            request_chain_from_peers(swarm);
+        }
+        _ => error!(target: logging::NET, "usage: partition <on|off>"),
+    }
+}
+
+/* Answers a query from the HTTP endpoint from the current state of the node */
+pub fn handle_http_query(query: http::HttpQuery, swarm: &Swarm<AppBehaviour>) {
+    let app = &swarm.behaviour().app;
+    let segments: Vec<&str> = query.path.trim_matches('/').split('/').collect();
+    let not_found = || (404, http::error_body(&format!("no such resource {}", query.path)));
+    let answer = match segments.as_slice() {
+        ["chain"] => (200, serde_json::to_string(&app.blocks).expect("can jsonify chain")),
+        ["block", id] => match id.parse::<usize>().ok().and_then(|id| app.blocks.get(id)) {
+            Some(block) => (200, serde_json::to_string(block).expect("can jsonify block")),
+            None => not_found(),
+        },
+        ["balance", address] => match resolve_address(app, address) {
+            Ok(key) => {
+                let balance = output::Balance {
+                    balance: key.map_or(0.0, |key| app.balance_of(&key)),
+                    address: address.to_string(),
+                };
+                (200, serde_json::to_string(&balance).expect("can jsonify balance"))
+            }
+            Err(e) => (400, http::error_body(&e)),
+        },
+        ["mempool"] => (200, serde_json::to_string(app.mempool.transactions()).expect("can jsonify mempool")),
+        ["stats"] => (200, serde_json::to_string(&app.stats()).expect("can jsonify stats")),
+        _ => not_found(),
+    };
+    // the client may have hung up in the meantime
+    let _ = query.reply.send(answer);
+}
+
+/* Runs on a timer: drops pending transactions older than `--mempool-ttl-secs` */
+pub fn handle_purge_mempool(swarm: &mut Swarm<AppBehaviour>) {
+    if CONFIG.mempool_ttl_secs <= 0 {
+        return;
+    }
+    let purged = swarm.behaviour_mut().app.mempool.purge_expired(CONFIG.mempool_ttl_secs);
+    if purged > 0 {
+        info!(target: logging::MEMPOOL, "purged {} expired transactions from the mempool", purged);
+    }
+}
+
+/* Reports the blocks a reorg orphaned; with `--json-output` the whole event goes to stdout */
+pub fn handle_reorg(event: ReorgEvent) {
+    output::emit(&event, |event| {
+        for hash in &event.orphaned_hashes {
+            info!(target: logging::CONSENSUS, "block {} orphaned by the reorg at height {}", hash, event.fork_height);
+        }
+    });
+}
+
+/* Re-publish our own pending transactions whose backoff has run out */
+pub fn handle_rebroadcast(swarm: &mut Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour_mut();
+    let due = behaviour.app.mempool.due_for_rebroadcast(Utc::now().timestamp());
+    if due.is_empty() {
+        return;
+    }
+    info!(target: logging::MEMPOOL, "rebroadcasting {} local transactions", due.len());
+    for tx in due {
+        let json = serde_json::to_string(&tx).expect("can jsonify transaction");
+        behaviour.publish(&TX_TOPIC, json);
+    }
+}
+
+/* `accept pause` freezes the local tip (incoming blocks and chains are dropped, chain
+   requests are still answered); `accept resume` unfreezes it and resyncs */
+pub fn handle_accept(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    match cmd.strip_prefix("accept").map(str::trim) {
+        Some("pause") => {
+            swarm.behaviour_mut().accept_paused = true;
+            info!(target: logging::NET, "block acceptance paused");
+        }
+        Some("resume") => {
+            swarm.behaviour_mut().accept_paused = false;
+            info!(target: logging::NET, "block acceptance resumed, resyncing with peers");
+            request_chain_from_peers(swarm);
+        }
+        _ => error!(target: logging::NET, "usage: accept <pause|resume>"),
+    }
+}
+
+/* Ask one peer for its chain and print how it differs from ours */
+pub fn handle_diff_peer(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    let peer_id = match cmd.strip_prefix("diff peer").map(str::trim) {
+        Some(peer_id) if !peer_id.is_empty() => peer_id.to_string(),
+        _ => {
+            error!(target: logging::NET, "usage: diff peer <peer_id>");
+            return;
+        }
+    };
+    let req = LocalChainRequest::new(peer_id.clone(), swarm.behaviour().app.chain_id());
+    let json = serde_json::to_string(&req).expect("can jsonify request");
+    let behaviour = swarm.behaviour_mut();
+    behaviour.pending_diff = Some(PendingDiff {
+        peer_id,
+        request_id: req.request_id.expect("new requests have an id"),
+        sent: Instant::now(),
+    });
+    behaviour.publish(&CHAIN_TOPIC, json);
+}
+
+/* `balance [address]`: confirmed balance, of our own wallet if no address is given */
+pub fn handle_balance(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour();
+    let address = match cmd.strip_prefix("balance").map(str::trim) {
+        Some("") | None => behaviour.wallet.public_key.clone(),
+        Some(address) => address.to_string(),
+    };
+    let key = match resolve_address(&behaviour.app, &address) {
+        Ok(key) => key,
+        Err(e) => {
+            error!(target: logging::NET, "{}", e);
+            return;
+        }
+    };
+    let balance = output::Balance {
+        // an address never seen on the chain holds nothing
+        balance: key.map_or(0.0, |key| behaviour.app.balance_of(&key)),
+        address,
+    };
+    output::emit(&balance, |balance| {
+        info!(target: logging::NET, "balance of {}: {}", balance.address, balance.balance);
+    });
+}
+
+/* Public keys pass through; a short address is checksum-checked and looked up on
+   the chain (Ok(None) if it never appeared there) */
+fn resolve_address(app: &Blockchain, address: &str) -> Result<Option<String>, String> {
+    if !is_short_address(address) {
+        return Ok(Some(address.to_string()));
+    }
+    if !is_valid_short_address(address) {
+        return Err(format!("address {} has a bad checksum, check it for typos", address));
+    }
+    Ok(app.public_key_for_address(address))
+}
+
+/* `ledger stats`: funded accounts and the value they hold.
+   `ledger dust <threshold>`: accounts holding less than the threshold. */
+pub fn handle_ledger(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    let balances = swarm.behaviour().app.account_balances();
+    match cmd.strip_prefix("ledger").map(str::trim) {
+        Some("stats") => {
+            let stats = output::LedgerStats {
+                accounts: balances.len(),
+                total_value: balances.values().sum(),
+            };
+            output::emit(&stats, |stats| {
+                info!(target: logging::NET, "{} funded accounts holding {}", stats.accounts, stats.total_value);
+            });
+        }
+        Some(rest) if rest.starts_with("dust") => {
+            let threshold = match rest["dust".len()..].trim().parse::<f64>() {
+                Ok(threshold) if threshold > 0.0 => threshold,
+                _ => {
+                    error!(target: logging::NET, "usage: ledger dust <threshold>");
+                    return;
+                }
+            };
+            let mut accounts: Vec<output::Balance> = balances
+                .into_iter()
+                .filter(|(_, balance)| *balance < threshold)
+                .map(|(address, balance)| output::Balance { address, balance })
+                .collect();
+            accounts.sort_by(|a, b| a.balance.total_cmp(&b.balance));
+            let dust = output::LedgerDust { threshold, accounts };
+            output::emit(&dust, |dust| {
+                info!(target: logging::NET, "{} accounts below {}", dust.accounts.len(), dust.threshold);
+                for account in &dust.accounts {
+                    info!(target: logging::NET, "{} {}", account.address, account.balance);
+                }
+            });
+        }
+        _ => error!(target: logging::NET, "usage: ledger stats | ledger dust <threshold>"),
+    }
+}
+
+/* `vanity <prefix>`: search for a key pair whose short address starts with the
+   hex prefix, on its own thread; `vanity stop` cancels it */
+pub fn handle_vanity(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour_mut();
+    let prefix = cmd.strip_prefix("vanity").map(str::trim).unwrap_or_default().to_lowercase();
+    if prefix == "stop" {
+        match behaviour.vanity_cancel.take() {
+            Some(cancel) => cancel.store(true, Ordering::Relaxed),
+            None => info!(target: logging::NET, "no vanity search running"),
+        }
+        return;
+    }
+    if prefix.is_empty() || prefix.len() > MAX_VANITY_PREFIX_LEN || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        error!(target: logging::NET, "usage: vanity <1-{} hex characters> | vanity stop", MAX_VANITY_PREFIX_LEN);
+        return;
+    }
+    if let Some(previous) = behaviour.vanity_cancel.take() {
+        previous.store(true, Ordering::Relaxed);
+    }
+    let expected = 16u64.pow(prefix.len() as u32);
+    if prefix.len() > 2 {
+        warn!(target: logging::NET, "expect around {} attempts for a {}-character prefix, `vanity stop` cancels", expected, prefix.len());
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    behaviour.vanity_cancel = Some(cancel.clone());
+    std::thread::spawn(move || match find_vanity_key(&prefix, &cancel) {
+        Some((secret_key, public_key, attempts)) => {
+            let key = output::VanityKey { address: address_from_public_key(&public_key), public_key, secret_key, attempts };
+            output::emit(&key, |key| {
+                info!(target: logging::NET, "found after {} attempts", key.attempts);
+                info!(target: logging::NET, "address: {}", key.address);
+                info!(target: logging::NET, "public key: {}", key.public_key);
+                info!(target: logging::NET, "secret key: {}", key.secret_key);
+            });
+        }
+        None => info!(target: logging::NET, "vanity search for {} cancelled", prefix),
+    });
+}
+
+pub fn handle_whoami(swarm: &Swarm<AppBehaviour>) {
+    let whoami = output::WhoAmI {
+        peer_id: PEER_ID.to_string(),
+        address: swarm.behaviour().wallet.address(),
+        chain_id: swarm.behaviour().app.chain_id(),
+    };
+    output::emit(&whoami, |whoami| {
+        info!(target: logging::NET, "Peer Id: {}", whoami.peer_id);
+        info!(target: logging::NET, "Address: {}", whoami.address);
+        match &whoami.chain_id {
+            Some(chain_id) => info!(target: logging::NET, "Chain Id: {}", chain_id),
+            None => info!(target: logging::NET, "Chain Id: <no genesis yet>"),
+        }
+    });
+}
+
+/* `send <receiver> <amount> [fee]`: sign a transfer from our wallet, queue it and gossip it */
+pub fn handle_add_transaction(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    let args: Vec<&str> = cmd.strip_prefix("send").unwrap_or_default().split_whitespace().collect();
+    let (receiver, amount, fee) = match args.as_slice() {
+        [receiver, amount] => (receiver, amount.parse::<f64>(), Ok(0.0)),
+        [receiver, amount, fee] => (receiver, amount.parse::<f64>(), fee.parse::<f64>()),
+        _ => {
+            error!(target: logging::NET, "usage: send <receiver> <amount> [fee]");
+            return;
+        }
+    };
+    let (amount, fee) = match (amount, fee) {
+        (Ok(amount), Ok(fee)) => (amount, fee),
+        _ => {
+            error!(target: logging::NET, "amount and fee must be numbers");
+            return;
+        }
+    };
+
+    let behaviour = swarm.behaviour_mut();
+    let receiver = match resolve_address(&behaviour.app, receiver) {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            error!(target: logging::NET, "address {} hasn't been on the chain yet, send to its public key", receiver);
+            return;
+        }
+        Err(e) => {
+            error!(target: logging::NET, "{}", e);
+            return;
+        }
+    };
+    let remaining = match remaining_after_send(&behaviour.app, &behaviour.wallet.public_key, amount, fee) {
+        Ok(remaining) => remaining,
+        Err(remaining) => {
+            error!(
+                target: logging::NET,
+                "insufficient balance: sending {} with fee {} would leave {}",
+                amount, fee, remaining
+            );
+            return;
+        }
+    };
+    let mut tx = Transaction::new(behaviour.wallet.public_key.clone(), receiver, amount, fee);
+    tx.nonce = behaviour.app.next_nonce(&tx.sender);
+    tx.chain_id = behaviour.app.chain_id().unwrap_or_default();
+    if let Some(difficulty) = behaviour.app.genesis_config.tx_pow_difficulty {
+        tx.solve_pow(difficulty);
+    }
+    if let Err(e) = tx.sign(&behaviour.wallet) {
+        error!(target: logging::NET, "can't sign transaction: {}", e);
+        return;
+    }
+    if let Err(reason) = behaviour
+        .app
+        .validate_transaction(&tx)
+        .and_then(|_| behaviour.app.mempool.check_dependency_limits(&tx))
+    {
+        error!(target: logging::NET, "transaction rejected: {:?}", reason);
+        return;
+    }
+    let json = serde_json::to_string(&tx).expect("can jsonify transaction");
+    let tx_id = tx.id();
+    behaviour.app.mempool.add_local_transaction(tx);
+    info!(target: logging::NET, "broadcasting transaction {}, remaining balance {}", tx_id, remaining);
+    behaviour.publish(&TX_TOPIC, json);
+}
+
+/* "Pay X, keep the rest": what `sender` keeps after sending `amount` plus `fee`,
+   net of its pending spends. Err carries the (negative) rest when it would go
+   below zero. */
+pub fn remaining_after_send(app: &Blockchain, sender: &str, amount: f64, fee: f64) -> Result<f64, f64> {
+    let remaining = app.available_balance(sender) - amount - fee;
+    if remaining < -BALANCE_EPSILON {
+        Err(remaining)
+    } else {
+        Ok(remaining.max(0.0))
+    }
 }
 
 pub fn handle_print_chain(swarm: &Swarm<AppBehaviour>) {
-    info!("Local Blockchain:");
-    let pretty_json =
-        serde_json::to_string_pretty(&swarm.behaviour().app.blocks).expect("can jsonify blocks");
-    info!("{}", pretty_json);
+    output::emit(&swarm.behaviour().app.blocks, |blocks| {
+        info!(target: logging::NET, "Local Blockchain:");
+        let pretty_json = serde_json::to_string_pretty(blocks).expect("can jsonify blocks");
+        info!(target: logging::NET, "{}", pretty_json);
+    });
 }
 
-pub fn handle_create_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
-    if let Some(data) = cmd.strip_prefix("create b") {
-        let behaviour = swarm.behaviour_mut();
-        let transaction1 = Transaction {
-            amount: 10.0,
-            sender: "03638e59237924128f9c9be55d435ecfcac3c6f774641b1cf24873ebbacede6098".to_string(),
-            receiver: "a8668a61f0d237403fb31545eaa0dcd756dc33a609ecfcc777c8cb2c6dce8247".to_string(),
-            //signature: "".to_string(),
+fn log_transaction_checks(tx: &Transaction, checks: &[(&'static str, Result<(), TxRejectReason>)]) {
+    for (name, result) in checks {
+        match result {
+            Ok(()) => info!(target: logging::NET, "  {}: ok", name),
+            Err(reason) => info!(target: logging::NET, "  {}: FAILED ({:?})", name, reason),
+        }
+    }
+    let failed = checks.iter().filter(|(_, result)| result.is_err()).count();
+    info!(target: logging::NET, "transaction {}: {} of {} checks failed", tx.id(), failed, checks.len());
+}
+
+/* `lookup <hash>`: the block with this hash, or the block holding the transaction with this txid */
+pub fn handle_lookup(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    let hash = match cmd.strip_prefix("lookup").map(str::trim) {
+        Some(hash) if !hash.is_empty() => hash,
+        _ => {
+            error!(target: logging::NET, "usage: lookup <block hash | txid>");
+            return;
+        }
+    };
+    output::emit(&lookup(&swarm.behaviour().app, hash), |lookup| match lookup {
+        output::Lookup::Block { block } => {
+            let pretty_json = serde_json::to_string_pretty(block).expect("can jsonify block");
+            info!(target: logging::NET, "block #{}: {}", block.id, pretty_json);
+        }
+        output::Lookup::Transaction { block_id, transaction } => {
+            let pretty_json = serde_json::to_string_pretty(transaction).expect("can jsonify transaction");
+            info!(target: logging::NET, "transaction in block #{}: {}", block_id, pretty_json);
+        }
+        output::Lookup::NotFound { hash } => info!(target: logging::NET, "{} not found", hash),
+    });
+}
+
+/* What `lookup` finds for `hash`: block hashes are tried before txids */
+pub fn lookup(app: &Blockchain, hash: &str) -> output::Lookup {
+    if let Some(block) = app.find_block(hash) {
+        output::Lookup::Block { block: block.clone() }
+    } else if let Some((block_id, transaction)) = app.find_transaction(hash) {
+        output::Lookup::Transaction { block_id, transaction }
+    } else {
+        output::Lookup::NotFound { hash: hash.to_string() }
+    }
+}
+
+/* `get tx <txid>`: where a transaction was mined; the txid is the sha256 of its signing bytes */
+pub fn handle_get_tx(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    let tx_id = match cmd.strip_prefix("get tx").map(str::trim) {
+        Some(tx_id) if !tx_id.is_empty() => tx_id,
+        _ => {
+            error!(target: logging::NET, "usage: get tx <txid>");
+            return;
+        }
+    };
+    let lookup = match swarm.behaviour().app.find_transaction(tx_id) {
+        Some((block_id, transaction)) => output::Lookup::Transaction { block_id, transaction },
+        None => output::Lookup::NotFound { hash: tx_id.to_string() },
+    };
+    output::emit(&lookup, |lookup| match lookup {
+        output::Lookup::Transaction { block_id, transaction } => {
+            let pretty_json = serde_json::to_string_pretty(transaction).expect("can jsonify transaction");
+            info!(target: logging::NET, "transaction mined at height {}: {}", block_id, pretty_json);
+        }
+        _ => info!(target: logging::NET, "transaction {} not found", tx_id),
+    });
+}
+
+/* `path <txid>`: the merkle path proving a confirmed transaction is in its block */
+pub fn handle_merkle_path(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    let tx_id = match cmd.strip_prefix("path").map(str::trim) {
+        Some(tx_id) if !tx_id.is_empty() => tx_id,
+        _ => {
+            error!(target: logging::NET, "usage: path <txid>");
+            return;
+        }
+    };
+    let app = &swarm.behaviour().app;
+    let block = match app.find_transaction(tx_id) {
+        Some((block_id, _)) => &app.blocks[block_id as usize],
+        None => {
+            error!(target: logging::NET, "transaction {} is not in any block", tx_id);
+            return;
+        }
+    };
+    let path = match merkle_path(block, tx_id) {
+        Some(path) => path,
+        None => {
+            error!(target: logging::NET, "can't build a merkle path for {} in block #{}", tx_id, block.id);
+            return;
+        }
+    };
+    output::emit(&path, |path| {
+        info!(target: logging::NET, "transaction {} in block #{}", path.txid, path.block_id);
+        info!(target: logging::NET, "leaf:  {}", path.leaf);
+        for (level, step) in path.steps.iter().enumerate() {
+            info!(target: logging::NET, "{:>2} {:>5}: {}", level + 1, step.side, step.sibling);
+        }
+        info!(target: logging::NET, "root:  {}", path.merkle_root);
+    });
+}
+
+/* The path `path <txid>` prints, None if `tx_id` isn't in `block` */
+pub fn merkle_path(block: &Block, tx_id: &str) -> Option<output::MerklePath> {
+    let (leaf, proof) = merkle_proof(&block.transactions, tx_id)?;
+    Some(output::MerklePath {
+        txid: tx_id.to_string(),
+        block_id: block.id,
+        leaf,
+        steps: proof
+            .into_iter()
+            .map(|(sibling, sibling_is_left)| output::MerkleStep {
+                sibling,
+                side: if sibling_is_left { "left" } else { "right" },
+            })
+            .collect(),
+        merkle_root: block.merkle_root.clone(),
+    })
+}
+
+/* Re-validates a transaction against the chain as it was right before its block
+   (or the current chain if it is still pending) */
+pub fn handle_replaytx(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    let tx_id = match cmd.strip_prefix("replaytx").map(str::trim) {
+        Some(tx_id) if !tx_id.is_empty() => tx_id,
+        _ => {
+            error!(target: logging::NET, "usage: replaytx <txid>");
+            return;
+        }
+    };
+    let app = &swarm.behaviour().app;
+    if let Some((height, tx)) = app.find_transaction(tx_id) {
+        info!(target: logging::NET, "replaying transaction from block #{} against the state before it", height);
+        let checks = app.transaction_checks_on(&tx, &app.blocks[..height as usize]);
+        log_transaction_checks(&tx, &checks);
+    } else if let Some(tx) = app.mempool.get(tx_id) {
+        info!(target: logging::NET, "replaying pending transaction against the current state");
+        log_transaction_checks(tx, &app.transaction_checks(tx));
+    } else {
+        error!(target: logging::NET, "transaction {} not found in the chain or mempool", tx_id);
+    }
+}
+
+/* Runs every transaction check without touching the chain or mempool */
+pub fn handle_verify_transaction(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(json) = cmd.strip_prefix("verify transaction") {
+        match serde_json::from_str::<Transaction>(json.trim()) {
+            Ok(tx) => log_transaction_checks(&tx, &swarm.behaviour().app.transaction_checks(&tx)),
+            Err(e) => error!(target: logging::NET, "could not parse transaction: {}", e),
+        }
+    }
+}
+
+/* `hashrate [secs]`: blocks the node for the sample like mining does */
+pub fn handle_hashrate(cmd: &str) {
+    let secs = match cmd.strip_prefix("hashrate").map(str::trim) {
+        Some("") | None => HASHRATE_SAMPLE_SECS,
+        Some(n) => match n.parse::<u64>() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                error!(target: logging::NET, "usage: hashrate [seconds]");
+                return;
+            }
+        },
+    };
+    info!(target: logging::MINER, "measuring hashrate for {}s...", secs);
+    let (attempts, elapsed) = measure_hashrate(Duration::from_secs(secs));
+    let hashrate = output::Hashrate {
+        attempts,
+        seconds: elapsed.as_secs_f64(),
+        hashes_per_second: hashes_per_second(attempts, elapsed),
+    };
+    output::emit(&hashrate, |hashrate| {
+        info!(
+            target: logging::MINER,
+            "hashrate: {:.0} H/s ({} hashes in {:.2}s)",
+            hashrate.hashes_per_second, hashrate.attempts, hashrate.seconds
+        );
+    });
+}
+
+/* Dev command: `gentx <n>` puts n generated transactions from our wallet into the mempool */
+pub fn handle_gentx(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    if !CONFIG.dev {
+        error!(target: logging::NET, "gentx is a dev command, start the node with --dev");
+        return;
+    }
+    let count = match cmd.strip_prefix("gentx").map(str::trim).map(str::parse::<usize>) {
+        Some(Ok(count)) => count,
+        _ => {
+            error!(target: logging::NET, "usage: gentx <n>");
+            return;
+        }
+    };
+    let behaviour = swarm.behaviour_mut();
+    let pow_difficulty = behaviour.app.genesis_config.tx_pow_difficulty;
+    let first_nonce = behaviour.app.next_nonce(&behaviour.wallet.public_key);
+    let chain_id = behaviour.app.chain_id().unwrap_or_default();
+    let generated = match generate_test_transactions(&behaviour.wallet, &chain_id, count, first_nonce, pow_difficulty) {
+        Ok(generated) => generated,
+        Err(e) => {
+            error!(target: logging::NET, "can't sign test transactions: {}", e);
+            return;
+        }
+    };
+    let mut added = 0;
+    for tx in generated {
+        if let Err(reason) = behaviour
+            .app
+            .validate_transaction(&tx)
+            .and_then(|_| behaviour.app.mempool.check_dependency_limits(&tx))
+        {
+            error!(target: logging::NET, "generated transaction rejected: {:?}", reason);
+            continue;
+        }
+        if behaviour.app.mempool.add_local_transaction(tx) {
+            added += 1;
+        }
+    }
+    info!(target: logging::NET, "added {} test transactions, mempool size {}", added, behaviour.app.mempool.len());
+}
+
+/* `prioritize <txid>` / `deprioritize <txid>`: puts a pending transaction ahead of
+   fee order in the blocks this node mines, or takes it back out */
+pub fn handle_prioritize(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    let mempool = &mut swarm.behaviour_mut().app.mempool;
+    match cmd.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["prioritize", txid] => {
+            if mempool.is_prioritized(txid) {
+                info!(target: logging::MEMPOOL, "transaction {} is already prioritized", txid);
+            } else if mempool.prioritize(txid) {
+                info!(target: logging::MEMPOOL, "transaction {} will be mined ahead of fee order", txid);
+            } else {
+                error!(target: logging::MEMPOOL, "no pending transaction {}", txid);
+            }
+        }
+        ["deprioritize", txid] => {
+            if mempool.deprioritize(txid) {
+                info!(target: logging::MEMPOOL, "transaction {} is back in fee order", txid);
+            } else {
+                error!(target: logging::MEMPOOL, "transaction {} isn't prioritized", txid);
+            }
+        }
+        _ => error!(target: logging::NET, "usage: prioritize <txid> | deprioritize <txid>"),
+    }
+}
+
+/* `mempool graph`: which pending transactions wait on which */
+pub fn handle_mempool_graph(swarm: &Swarm<AppBehaviour>) {
+    let graph = output::MempoolGraph {
+        transactions: swarm
+            .behaviour()
+            .app
+            .mempool
+            .dependency_graph()
+            .into_iter()
+            .map(|(txid, depends_on)| output::MempoolNode { txid, depends_on })
+            .collect(),
+    };
+    output::emit(&graph, |graph| {
+        info!(target: logging::MEMPOOL, "{} pending transactions", graph.transactions.len());
+        for node in &graph.transactions {
+            if node.depends_on.is_empty() {
+                info!(target: logging::MEMPOOL, "{}", node.txid);
+            } else {
+                info!(target: logging::MEMPOOL, "{} -> {}", node.txid, node.depends_on.join(", "));
+            }
+        }
+    });
+}
+
+pub fn handle_stats(swarm: &Swarm<AppBehaviour>) {
+    output::emit(&swarm.behaviour().app.stats(), |stats| {
+        match &stats.chain_id {
+            Some(chain_id) => info!(target: logging::NET, "chain id: {}", chain_id),
+            None => info!(target: logging::NET, "chain id: <no genesis yet>"),
+        }
+        info!(target: logging::NET, "height: {}", stats.height);
+        info!(
+            target: logging::NET,
+            "transactions: {} confirmed, {} pending",
+            stats.total_transactions, stats.mempool_size
+        );
+        info!(target: logging::NET, "difficulty: {} bits", stats.difficulty);
+        match stats.transactions_per_second {
+            Some(tps) => info!(target: logging::NET, "throughput: {:.3} tx/s over the last {} blocks", tps, TPS_WINDOW_BLOCKS),
+            None => info!(target: logging::NET, "throughput: not enough blocks yet"),
+        }
+        match stats.estimated_hashrate {
+            Some(rate) => info!(target: logging::NET, "network hashrate: ~{:.0} H/s over the last {} blocks", rate, TPS_WINDOW_BLOCKS),
+            None => info!(target: logging::NET, "network hashrate: not enough blocks yet"),
+        }
+        info!(
+            target: logging::NET,
+            "supply: issued {}, burned {}, circulating {}",
+            stats.issued, stats.burned, stats.circulating_supply
+        );
+        info!(target: logging::NET, "validation cache hits: {}", stats.validation_cache_hits);
+    });
+}
+
+pub fn handle_supply(swarm: &Swarm<AppBehaviour>) {
+    let app = &swarm.behaviour().app;
+    let supply = output::Supply {
+        issued: app.total_issued(),
+        burned: app.total_burned(),
+        circulating: app.circulating_supply(),
+    };
+    output::emit(&supply, |supply| {
+        info!(
+            target: logging::NET,
+            "supply: issued {}, burned {}, circulating {}",
+            supply.issued, supply.burned, supply.circulating
+        );
+    });
+}
+
+pub fn handle_finality(swarm: &Swarm<AppBehaviour>) {
+    let app = &swarm.behaviour().app;
+    let depth = CONFIG.finality_depth;
+    let finality = output::Finality {
+        depth,
+        height: app.blocks.len(),
+        safe_tip: app.safe_tip(depth).map(|b| b.id),
+        non_final: app.blocks.iter().map(|b| b.id).filter(|id| !app.is_final(*id, depth)).collect(),
+    };
+    output::emit(&finality, |finality| {
+        match finality.safe_tip {
+            Some(id) => info!(target: logging::NET, "safe tip: block #{} ({} confirmations required)", id, finality.depth),
+            None => info!(target: logging::NET, "no final blocks yet ({} confirmations required)", finality.depth),
+        }
+        info!(target: logging::NET, "not yet final: {:?}", finality.non_final);
+    });
+}
+
+pub fn handle_audit(swarm: &Swarm<AppBehaviour>) {
+    match swarm.behaviour().app.audit() {
+        Ok(()) => info!(target: logging::NET, "audit passed: {} blocks valid", swarm.behaviour().app.blocks.len()),
+        Err(index) => error!(target: logging::NET, "audit failed: block #{} is invalid", index),
+    }
+}
+
+/* Prints the exact bytes sign/verify operate on, hex-encoded */
+pub fn handle_signbytes(cmd: &str) {
+    if let Some(json) = cmd.strip_prefix("signbytes") {
+        match signbytes(json.trim()) {
+            Ok(bytes) => info!(target: logging::NET, "{}", bytes),
+            Err(e) => error!(target: logging::NET, "could not parse transaction: {}", e),
+        }
+    }
+}
+
+/* Hex signing bytes of a transaction given as JSON */
+pub fn signbytes(json: &str) -> serde_json::Result<String> {
+    serde_json::from_str::<Transaction>(json).map(|tx| hex::encode(tx.signing_bytes()))
+}
+
+/* `calchash <id> <prev_hash> <timestamp> <data> <nonce> [merkle_root]`: the block hash
+   calculate_hash gives for these header fields. `data` is one word here; the merkle
+   root defaults to that of a block without transactions. */
+pub fn handle_calchash(cmd: &str) {
+    match calchash(cmd) {
+        Ok(hash) => info!(target: logging::NET, "{} ({} leading zero bits)", hex::encode(&hash), leading_zero_bits(&hash)),
+        Err(e) => error!(target: logging::NET, "{}", e),
+    }
+}
+
+pub fn calchash(cmd: &str) -> Result<Vec<u8>, &'static str> {
+    let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+    let (id, previous_hash, timestamp, data, nonce, root) = match args.as_slice() {
+        [id, previous_hash, timestamp, data, nonce] => (id, *previous_hash, timestamp, data, nonce, merkle_root(&[])),
+        [id, previous_hash, timestamp, data, nonce, root] => (id, *previous_hash, timestamp, data, nonce, root.to_string()),
+        _ => return Err("usage: calchash <id> <prev_hash> <timestamp> <data> <nonce> [merkle_root]"),
+    };
+    let is_hash = |s: &str| s.len() == 64 && hex::decode(s).is_ok();
+    if !is_hash(previous_hash) || !is_hash(&root) {
+        return Err("prev_hash and merkle_root must be 64 hex characters");
+    }
+    match (id.parse::<u64>(), timestamp.parse::<i64>(), nonce.parse::<u64>()) {
+        (Ok(id), Ok(timestamp), Ok(nonce)) => Ok(calculate_hash(id, timestamp, previous_hash, data, &root, nonce)),
+        _ => Err("id, timestamp and nonce must be integers"),
+    }
+}
+
+/* Ctrl-C or `quit`: saves the chain and the mempool before the event loop exits.
+   Failures are logged; shutdown goes ahead either way. */
+pub fn handle_shutdown(swarm: &mut Swarm<AppBehaviour>) {
+    let app = &mut swarm.behaviour_mut().app;
+    app.persist();
+    if !CONFIG.in_memory {
+        match app.mempool.save_to_file(&CONFIG.mempool_file) {
+            Ok(()) => info!(
+                target: logging::MEMPOOL,
+                "saved {} pending transactions to {}",
+                app.mempool.len(),
+                CONFIG.mempool_file.display()
+            ),
+            Err(e) => error!(target: logging::MEMPOOL, "can't save the mempool to {}: {}", CONFIG.mempool_file.display(), e),
+        }
+    }
+    info!(target: logging::NET, "shut down cleanly at height {}", app.blocks.len());
+}
+
+/* Dials every `--dial` address. Gossipsub meshes with peers found this way by
+   itself once the connection is up, see `handle_connection_established`. */
+pub fn dial_bootstrap_peers(swarm: &mut Swarm<AppBehaviour>) {
+    for addr in &CONFIG.dial {
+        let multiaddr: Multiaddr = match addr.parse() {
+            Ok(multiaddr) => multiaddr,
+            Err(e) => {
+                error!(target: logging::NET, "invalid dial address {}: {}", addr, e);
+                continue;
+            }
         };
+        match Swarm::dial_addr(swarm, multiaddr) {
+            Ok(()) => info!(target: logging::NET, "dialing {}", addr),
+            Err(e) => error!(target: logging::NET, "can't dial {}: {:?}", addr, e),
+        }
+    }
+}
+
+/* A connection came up, dialed by either side. mDNS only reports peers on the
+   local network, so this is how dialed peers and the nodes dialing us are counted;
+   peers off the allow-list are blacklisted and disconnected. Gossipsub meshes the
+   rest by itself, and the mempool follows once they subscribe. */
+pub fn handle_connection_established(swarm: &mut Swarm<AppBehaviour>, peer: PeerId) {
+    if CONFIG.is_peer_allowed(&peer) {
+        swarm.behaviour_mut().partial_view.insert(peer);
+        return;
+    }
+    info!(target: logging::NET, "disconnecting peer {} not on the allow-list", peer);
+    swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+    let _ = swarm.disconnect_peer_id(peer);
+}
+
+/* Drop the invalid suffix of the local chain and fetch it again from peers */
+pub fn handle_repair(swarm: &mut Swarm<AppBehaviour>) {
+    let dropped = swarm.behaviour_mut().app.truncate_to_valid();
+    if dropped == 0 {
+        info!(target: logging::NET, "chain is valid, nothing to repair");
+        return;
+    }
+    info!(target: logging::NET, "dropped {} invalid blocks, resyncing with peers", dropped);
+    request_chain_from_peers(swarm);
+}
+
+/* `restore-backup` lists the chain backups, newest first; `restore-backup <n>` loads one */
+pub fn handle_restore_backup(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    let app = &mut swarm.behaviour_mut().app;
+    match cmd.strip_prefix("restore-backup").map(str::trim) {
+        Some("") | None => {
+            let backups = match &app.chain_file {
+                Some(chain_file) => Blockchain::list_backups(chain_file).unwrap_or_default(),
+                None => vec![],
+            };
+            if backups.is_empty() {
+                info!(target: logging::NET, "no chain backups (start the node with --backups <k>)");
+            }
+            for (i, backup) in backups.iter().enumerate() {
+                info!(target: logging::NET, "{}: {}", i + 1, backup.display());
+            }
+        }
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => match app.restore_backup(n) {
+                Ok(backup) => info!(target: logging::NET, "restored {} blocks from {}", app.blocks.len(), backup.display()),
+                Err(e) => error!(target: logging::NET, "can't restore backup #{}: {}", n, e),
+            },
+            Err(_) => error!(target: logging::NET, "usage: restore-backup [n]"),
+        },
+    }
+}
+
+pub fn handle_export_csv(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    match cmd.strip_prefix("export csv").map(str::trim) {
+        Some(path) if !path.is_empty() => {
+            match std::fs::write(path, swarm.behaviour().app.to_csv()) {
+                Ok(()) => info!(target: logging::NET, "exported chain to {}", path),
+                Err(e) => error!(target: logging::NET, "could not export chain to {}: {}", path, e),
+            }
+        }
+        _ => error!(target: logging::NET, "usage: export csv <path>"),
+    }
+}
+
+/* Mining while isolated builds on a possibly stale tip, so require enough peers unless forced */
+pub fn can_create_block(connected_peers: usize, min_peers: usize, force: bool) -> bool {
+    force || connected_peers >= min_peers
+}
 
-        let transaction2 = Transaction {
-            amount: 10.0,
-            sender: "03638e5op1239dnvcnrkdf39rk435ecfcac3c6f774641b1cf24873ebbacede6098".to_string(),
-            receiver: "a8668a61ffwef213lasddgtvnb9329rjd4s67aapsfkcfln777c8cb2c6dce8247".to_string(),
-            //signature: "".to_string(),
+pub fn handle_create_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    if let Some(data) = cmd.strip_prefix("create b") {
+        let (force, data) = match data.trim_start().strip_prefix("--force") {
+            Some(rest) => (true, rest),
+            None => (false, data),
         };
-        let collect_tx: Vec<Transaction> = vec![transaction1,transaction2];
+        let connected = swarm.network_info().num_peers();
+        if !can_create_block(connected, CONFIG.min_peers, force) {
+            error!(
+                target: logging::NET,
+                "refusing to create a block with {} connected peers (need {}), use `create b --force` to override",
+                connected, CONFIG.min_peers
+            );
+            return;
+        }
+        let behaviour = swarm.behaviour_mut();
         let latest_block = behaviour
             .app
             .blocks
             .last()
             .expect("there is at least one block");
+        let height = latest_block.id + 1;
+        // an empty mempool still gives a valid block: just the coinbase
+        let pending = behaviour.app.block_template();
+        let included = pending.len();
+        let coinbase = Transaction::coinbase(
+            behaviour.wallet.public_key.clone(),
+            behaviour.app.coinbase_amount(height, &pending),
+            height,
+        );
+        let mut transactions = vec![coinbase];
+        transactions.extend(pending);
         let block = Block::new(
-            latest_block.id + 1,
+            height,
             latest_block.hash.clone(),
+            latest_block.timestamp,
             data.to_owned(),
-            collect_tx,
+            transactions,
+            behaviour.app.expected_difficulty(height),
         );
         let json = serde_json::to_string(&block).expect("can jsonify request");
-        behaviour.app.blocks.push(block);
-        info!("broadcasting new block");
-        behaviour
-            .floodsub
-            .publish(BLOCK_TOPIC.clone(), json.as_bytes());
+        // validated like any other block; acceptance also takes its transactions out of the mempool
+        if let Err(e) = behaviour.app.try_add_block(block) {
+            error!(target: logging::MINER, "mined block was not accepted: {}", e);
+            return;
+        }
+        info!(target: logging::NET, "broadcasting new block with {} transactions from the mempool", included);
+        behaviour.publish(&BLOCK_TOPIC, json);
     }
 }
 
@@ -267,3 +1617,266 @@ pub fn handle_create_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
 ]
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{mine, temp_dir, test_chain};
+    use crate::key::verify_signature;
+    use libp2p::gossipsub::{GossipsubMessage, MessageId};
+
+    #[test]
+    fn signbytes_are_the_signed_bytes() {
+        let key = KeyMaster::new();
+        let mut tx = Transaction::new(key.public_key.clone(), KeyMaster::new().public_key, 2.0, 0.5);
+        tx.sign(&key).unwrap();
+
+        let bytes = signbytes(&serde_json::to_string(&tx).unwrap()).unwrap();
+        assert_eq!(bytes, hex::encode(tx.signing_bytes()));
+        let message = String::from_utf8(hex::decode(&bytes).unwrap()).unwrap();
+        assert!(verify_signature(&tx.sender, &message, &tx.signature));
+
+        assert!(signbytes("{").is_err());
+    }
+
+    #[test]
+    fn printed_merkle_path_folds_to_the_root() {
+        use sha2::{Digest, Sha256};
+        // what someone checking the printout by hand computes: sha256(0x01 || left || right)
+        let node = |left: &str, right: &str| {
+            let mut hasher = Sha256::new();
+            hasher.update([1u8]);
+            hasher.update(left.as_bytes());
+            hasher.update(right.as_bytes());
+            format!("{:x}", hasher.finalize())
+        };
+
+        let key = KeyMaster::new();
+        let mut transactions = vec![Transaction::coinbase(key.public_key.clone(), 10.0, 1)];
+        for nonce in 0..4 {
+            let mut tx = Transaction::new(key.public_key.clone(), KeyMaster::new().public_key, 1.0, 0.0);
+            tx.nonce = nonce;
+            tx.sign(&key).unwrap();
+            transactions.push(tx);
+        }
+        let block = Block::new(1, "00".repeat(32), 0, "data".to_string(), transactions, 0);
+
+        for tx in &block.transactions {
+            let path = merkle_path(&block, &tx.id()).unwrap();
+            let folded = path.steps.iter().fold(path.leaf.clone(), |hash, step| match step.side {
+                "left" => node(&step.sibling, &hash),
+                _ => node(&hash, &step.sibling),
+            });
+            assert_eq!(folded, block.merkle_root);
+        }
+        assert!(merkle_path(&block, "unknown").is_none());
+    }
+
+    #[test]
+    fn calchash_matches_calculate_hash() {
+        let block = Block::new(1, "ab".repeat(32), 1_000, "hello".to_string(), vec![], 4);
+        let cmd = format!("calchash {} {} {} {} {}", block.id, block.previous_hash, block.timestamp, block.data, block.nonce);
+        assert_eq!(hex::encode(calchash(&cmd).unwrap()), block.hash);
+
+        let with_root = format!("{} {}", cmd, block.merkle_root);
+        assert_eq!(hex::encode(calchash(&with_root).unwrap()), block.hash);
+
+        assert!(calchash("calchash 1 2 3").is_err());
+        assert!(calchash(&format!("calchash x {} 1 d 0", block.previous_hash)).is_err());
+        assert!(calchash("calchash 1 short 1 d 0").is_err());
+    }
+
+    #[test]
+    fn peer_key_file_keeps_the_peer_id() {
+        let path = crate::blockchain::tests::temp_dir("peer_key").join("peer.key");
+        let created = PeerId::from(load_or_create_peer_key(&path).public());
+        assert!(path.exists());
+        let loaded = PeerId::from(load_or_create_peer_key(&path).public());
+        assert_eq!(created, loaded);
+    }
+
+    #[test]
+    fn lookup_finds_blocks_and_transactions() {
+        use crate::blockchain::tests::{mine, test_chain, transfer};
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+        let tx = transfer(&chain, &key, 1.0, 0.0);
+        let block = mine(&mut chain, &key, vec![tx.clone()]);
+
+        match lookup(&chain, &block.hash) {
+            output::Lookup::Block { block: found } => assert_eq!(found.hash, block.hash),
+            other => panic!("expected the block, got {:?}", other),
+        }
+        match lookup(&chain, &tx.id()) {
+            output::Lookup::Transaction { block_id, transaction } => {
+                assert_eq!(block_id, 2);
+                assert_eq!(transaction.id(), tx.id());
+            }
+            other => panic!("expected the transaction, got {:?}", other),
+        }
+        match lookup(&chain, "unknown") {
+            output::Lookup::NotFound { hash } => assert_eq!(hash, "unknown"),
+            other => panic!("expected nothing, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn send_may_empty_the_balance_but_not_overdraw_it() {
+        use crate::blockchain::tests::{mine, test_chain, transfer};
+        let mut chain = test_chain();
+        let key = KeyMaster::new();
+        mine(&mut chain, &key, vec![]);
+
+        assert_eq!(remaining_after_send(&chain, &key.public_key, 7.0, 1.0), Ok(2.0));
+        assert_eq!(remaining_after_send(&chain, &key.public_key, 9.0, 1.0), Ok(0.0));
+        assert_eq!(remaining_after_send(&chain, &key.public_key, 9.5, 1.0), Err(-0.5));
+
+        // pending spends count
+        assert!(chain.mempool.add_transaction(transfer(&chain, &key, 4.0, 0.0)));
+        assert_eq!(remaining_after_send(&chain, &key.public_key, 5.0, 1.0), Ok(0.0));
+        assert_eq!(remaining_after_send(&chain, &key.public_key, 6.0, 1.0), Err(-1.0));
+    }
+
+    #[test]
+    fn watchdog_restarts_only_after_silence_with_peers() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(Duration::from_secs(30));
+        watchdog.record_exchange(start);
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!watchdog.should_restart(at(10), 2));
+        // a message resets the clock
+        watchdog.record_exchange(at(20));
+        assert!(!watchdog.should_restart(at(45), 2));
+        assert!(watchdog.should_restart(at(50), 2));
+        // silence without peers is expected
+        assert!(!watchdog.should_restart(at(50), 0));
+
+        assert!(watchdog.heartbeat_due(at(0)));
+        watchdog.record_heartbeat(at(0));
+        assert!(!watchdog.heartbeat_due(at(5)));
+        assert!(watchdog.heartbeat_due(at(10)));
+
+        let disabled = Watchdog::new(Duration::ZERO);
+        assert!(!disabled.should_restart(at(3600), 2));
+        assert!(!disabled.heartbeat_due(at(3600)));
+    }
+
+    #[test]
+    fn block_creation_needs_enough_peers_unless_forced() {
+        assert!(!can_create_block(1, 2, false));
+        assert!(can_create_block(2, 2, false));
+        assert!(can_create_block(3, 2, false));
+        assert!(can_create_block(0, 2, true));
+        // the default threshold lets a lone node mine
+        assert!(can_create_block(0, 0, false));
+    }
+
+    async fn test_behaviour(app: Blockchain) -> AppBehaviour {
+        let (response_sender, _) = mpsc::unbounded_channel();
+        AppBehaviour::new(app, response_sender).await
+    }
+
+    #[tokio::test]
+    async fn only_allow_listed_peers_are_heard() {
+        let friend = PeerId::random();
+        let stranger = PeerId::random();
+        let path = temp_dir("allow_list").join("allow-list");
+        fs::write(&path, format!("# the only peer we talk to\n{}\n", friend)).unwrap();
+        let config = NodeConfig::parse(["--allow-list".to_string(), path.display().to_string()].into_iter());
+
+        let behaviour = test_behaviour(test_chain()).await;
+        assert!(behaviour.admits(&config, &friend));
+        assert!(!behaviour.admits(&config, &stranger));
+        // without a list the network is open
+        assert!(behaviour.admits(&NodeConfig::default(), &stranger));
+    }
+
+    #[tokio::test]
+    async fn rediscovered_peer_is_added_once() {
+        let mut behaviour = test_behaviour(test_chain()).await;
+        let peer = PeerId::random();
+        assert!(behaviour.add_to_view(peer));
+        assert!(!behaviour.add_to_view(peer));
+        assert_eq!(behaviour.partial_view.len(), 1);
+        assert!(behaviour.add_to_view(PeerId::random()));
+        assert_eq!(behaviour.partial_view.len(), 2);
+    }
+
+    /* `payload` as gossipsub hands it over once it arrived from `source` */
+    fn gossip(source: PeerId, topic: &Topic, payload: &impl Serialize) -> GossipsubEvent {
+        GossipsubEvent::Message {
+            propagation_source: source,
+            message_id: MessageId::new(b"test"),
+            message: GossipsubMessage {
+                source: Some(source),
+                data: serde_json::to_vec(payload).unwrap(),
+                sequence_number: Some(1),
+                topic: topic.hash(),
+            },
+        }
+    }
+
+    /* `chain`'s blocks sent in answer to our chain request */
+    fn chain_response(chain: &Blockchain) -> ChainResponse {
+        ChainResponse {
+            blocks: chain.blocks.clone(),
+            receiver: PEER_ID.to_string(),
+            chain_id: chain.chain_id(),
+            request_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn paused_node_drops_blocks_and_syncs_after_resume() {
+        let mut remote = test_chain();
+        let miner = KeyMaster::new();
+        mine(&mut remote, &miner, vec![]);
+        mine(&mut remote, &miner, vec![]);
+        let peer = PeerId::random();
+
+        let mut behaviour = test_behaviour(test_chain()).await;
+        behaviour.accept_paused = true;
+        behaviour.inject_event(gossip(peer, &BLOCK_TOPIC, &remote.blocks[1]));
+        behaviour.inject_event(gossip(peer, &CHAIN_TOPIC, &chain_response(&remote)));
+        assert_eq!(behaviour.app.blocks.len(), 1);
+
+        behaviour.accept_paused = false;
+        behaviour.inject_event(gossip(peer, &CHAIN_TOPIC, &chain_response(&remote)));
+        assert_eq!(behaviour.app.blocks.len(), 3);
+        assert_eq!(behaviour.app.blocks[2].hash, remote.blocks[2].hash);
+    }
+
+    #[tokio::test]
+    async fn partitioned_node_drops_blocks_and_adopts_the_winner_after_healing() {
+        let mut remote = test_chain();
+        let other = KeyMaster::new();
+        for _ in 0..3 {
+            mine(&mut remote, &other, vec![]);
+        }
+        let peer = PeerId::random();
+
+        let mut behaviour = test_behaviour(test_chain()).await;
+        behaviour.partitioned = true;
+        // mining goes on while isolated
+        let miner = KeyMaster::new();
+        let local_block = mine(&mut behaviour.app, &miner, vec![]);
+        behaviour.inject_event(gossip(peer, &BLOCK_TOPIC, &remote.blocks[1]));
+        behaviour.inject_event(gossip(peer, &CHAIN_TOPIC, &chain_response(&remote)));
+        assert_eq!(behaviour.app.blocks.len(), 2);
+        assert_eq!(behaviour.app.blocks[1].hash, local_block.hash);
+
+        behaviour.partitioned = false;
+        behaviour.inject_event(gossip(peer, &CHAIN_TOPIC, &chain_response(&remote)));
+        assert_eq!(behaviour.app.blocks.len(), 4);
+        assert_eq!(behaviour.app.blocks[3].hash, remote.blocks[3].hash);
+    }
+
+    #[test]
+    fn wallet_seed_gives_the_same_wallet_on_every_start() {
+        let config = || NodeConfig::parse(["--wallet-seed".to_string(), "correct horse battery staple".to_string()].into_iter());
+        assert_eq!(load_wallet(&config()).public_key, load_wallet(&config()).public_key);
+        assert_ne!(load_wallet(&NodeConfig::default()).public_key, load_wallet(&NodeConfig::default()).public_key);
+    }
+}
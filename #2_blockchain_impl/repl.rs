@@ -0,0 +1,137 @@
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::io::IsTerminal;
+use tokio::{
+    io::{stdin, AsyncBufReadExt, BufReader},
+    spawn,
+    sync::mpsc,
+};
+use log::error;
+use crate::logging;
+
+/* Command names offered by tab-completion; keep in sync with the match in main.rs */
+pub const COMMANDS: &[&str] = &[
+    "ls p",
+    "ls c",
+    "get block",
+    "get tx",
+    "create b",
+    "send",
+    "whoami",
+    "balance",
+    "ledger stats",
+    "ledger dust",
+    "audit",
+    "repair",
+    "stats",
+    "supply",
+    "finality",
+    "hashrate",
+    "vanity",
+    "vanity stop",
+    "mempool graph",
+    "prioritize",
+    "deprioritize",
+    "gentx",
+    "diff peer",
+    "accept pause",
+    "accept resume",
+    "partition on",
+    "partition off",
+    "verify transaction",
+    "replaytx",
+    "lookup",
+    "path",
+    "signbytes",
+    "calchash",
+    "export csv",
+    "quit",
+    "restore-backup",
+];
+
+pub fn complete_command(prefix: &str) -> Vec<String> {
+    COMMANDS
+        .iter()
+        .filter(|cmd| cmd.starts_with(prefix))
+        .map(|cmd| cmd.to_string())
+        .collect()
+}
+
+struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok((0, complete_command(&line[..pos])))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+/* Feeds stdin lines into `sender`. A terminal gets a line editor with history and
+   completion (on its own thread, rustyline blocks); piped input is read as-is. */
+pub fn spawn_input(sender: mpsc::UnboundedSender<String>) {
+    if std::io::stdin().is_terminal() {
+        std::thread::spawn(move || {
+            let mut editor = Editor::<CommandHelper>::new();
+            editor.set_helper(Some(CommandHelper));
+            loop {
+                match editor.readline("> ") {
+                    Ok(line) => {
+                        editor.add_history_entry(line.as_str());
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    // the line editor holds the terminal in raw mode, so Ctrl-C arrives
+                    // here rather than as a signal; turn it into the same clean shutdown
+                    Err(ReadlineError::Interrupted) => {
+                        let _ = sender.send("quit".to_string());
+                        break;
+                    }
+                    Err(ReadlineError::Eof) => break,
+                    Err(e) => {
+                        error!(target: logging::NET, "can't read line from stdin: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    } else {
+        spawn(async move {
+            let mut lines = BufReader::new(stdin()).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_command_prefixes() {
+        assert_eq!(complete_command("ls"), vec!["ls p", "ls c"]);
+        assert_eq!(complete_command("vanity"), vec!["vanity", "vanity stop"]);
+        assert_eq!(complete_command("restore"), vec!["restore-backup"]);
+        assert!(complete_command("frobnicate").is_empty());
+        assert_eq!(complete_command("").len(), COMMANDS.len());
+    }
+}
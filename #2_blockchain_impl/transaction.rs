@@ -1,10 +1,274 @@
+use chrono::Utc;
+use rand::Rng;
+use secp256k1::PublicKey;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use crate::block::leading_zero_bits;
+use crate::key::{hash_string, verify_signature, KeyError, KeyMaster};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub sender: String,
     pub receiver: String,
     pub amount: f64,
-    //pub signature: String,
+    #[serde(default)]
+    pub fee: f64,
+    pub created_at: i64,
+    /* sender's sequence number: 0 for its first transaction, then one more for each
+       next one; a block may only include the sender's next unused nonces */
+    #[serde(default)]
+    pub nonce: u64,
+    /* free text attached by the sender */
+    #[serde(default)]
+    pub memo: String,
+    /* Blockchain::chain_id of the network it is meant for; signed, so the
+       transaction can't be replayed on a network with another genesis */
+    #[serde(default)]
+    pub chain_id: String,
+    /* anti-spam proof of work, see solve_pow; not part of the signing bytes */
+    #[serde(default)]
+    pub pow_nonce: u64,
+    /* sender's signature over signing_payload; empty until signed */
+    #[serde(default)]
+    pub signature: String,
 }
 
+/* Sends to this address destroy coins: it isn't a valid public key, so nobody can spend from it */
+pub const BURN_ADDRESS: &str = "000000000000000000000000000000000000000000000000000000000000000000";
+
+/* Sender of the block reward transaction; not a public key, so no ordinary transaction can use it */
+pub const COINBASE_SENDER: &str = "COINBASE";
+
+/* Decimal places amounts are rounded to in signing bytes */
+pub const AMOUNT_DECIMALS: usize = 8;
+
+/* Fixed-precision decimal text for an amount. Float-to-JSON output isn't guaranteed to be
+   identical everywhere, and these bytes feed signatures and txids, so they must be. */
+pub fn canonical_amount(amount: f64) -> String {
+    format!("{:.*}", AMOUNT_DECIMALS, amount)
+}
+
+/* How far ahead of the local clock a transaction's created_at may be */
+pub const MAX_TX_FUTURE_DRIFT_SECS: i64 = 2 * 60 * 60;
+
+impl Transaction {
+    pub fn is_burn(&self) -> bool {
+        self.receiver == BURN_ADDRESS
+    }
+
+    /* The first transaction of a mined block, paying the block reward to its miner.
+       Its nonce is the block height, which keeps coinbase txids unique. */
+    pub fn coinbase(miner: String, reward: f64, height: u64) -> Self {
+        let mut tx = Transaction::new(COINBASE_SENDER.to_string(), miner, reward, 0.0);
+        tx.nonce = height;
+        tx
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == COINBASE_SENDER
+    }
+
+    pub fn new(sender: String, receiver: String, amount: f64, fee: f64) -> Self {
+        Self {
+            sender,
+            receiver,
+            amount,
+            fee,
+            created_at: Utc::now().timestamp(),
+            nonce: 0,
+            memo: String::new(),
+            chain_id: String::new(),
+            pow_nonce: 0,
+            signature: String::new(),
+        }
+    }
+
+    /* Canonical text that gets signed and verified. It must cover every field
+       except signature and pow_nonce, or that field could be changed without
+       breaking the signature. json! keeps keys sorted, so the output doesn't
+       depend on struct field order. */
+    pub fn signing_payload(&self) -> String {
+        serde_json::json!({
+            "sender": self.sender,
+            "receiver": self.receiver,
+            "amount": canonical_amount(self.amount),
+            "fee": canonical_amount(self.fee),
+            "created_at": self.created_at,
+            "nonce": self.nonce,
+            "memo": self.memo,
+            "chain_id": self.chain_id,
+        })
+        .to_string()
+    }
+
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        self.signing_payload().into_bytes()
+    }
+
+    /* Sign as `key`, which must hold the sender's secret key */
+    pub fn sign(&mut self, key: &KeyMaster) -> Result<(), KeyError> {
+        self.signature = key.sign(self.signing_payload())?;
+        Ok(())
+    }
+
+    /* The signature is the sender's (sender is the public key) over the current fields */
+    pub fn verify(&self) -> bool {
+        verify_signature(&self.sender, &self.signing_payload(), &self.signature)
+    }
+
+    /* sha256(signing bytes || pow_nonce) */
+    pub fn pow_hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.signing_bytes());
+        hasher.update(self.pow_nonce.to_le_bytes());
+        hasher.finalize().to_vec()
+    }
+
+    pub fn has_pow(&self, difficulty: u32) -> bool {
+        leading_zero_bits(&self.pow_hash()) >= difficulty
+    }
+
+    /* Search pow_nonce until pow_hash has `difficulty` leading zero bits */
+    pub fn solve_pow(&mut self, difficulty: u32) {
+        self.pow_nonce = 0;
+        while !self.has_pow(difficulty) {
+            self.pow_nonce += 1;
+        }
+    }
+
+    /* What the transaction contributes to its block's merkle tree: the signing
+       payload plus the signature and pow_nonce it leaves out. Built from the
+       canonical text rather than serde_json::to_string, whose float output
+       could differ between nodes and split them on the merkle root. */
+    pub fn merkle_leaf_data(&self) -> String {
+        serde_json::json!({
+            "payload": self.signing_payload(),
+            "signature": self.signature,
+            "pow_nonce": self.pow_nonce,
+        })
+        .to_string()
+    }
+
+    /* txid: sha256 of the signing bytes, hex-encoded */
+    pub fn id(&self) -> String {
+        hash_string(&self.signing_payload())
+    }
+}
+
+/* Why a transaction was refused by `Blockchain::validate_transaction` */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxRejectReason {
+    InvalidAddress,
+    InvalidSignature,
+    InvalidAmount,
+    InvalidFee,
+    FeeTooLow,
+    InsufficientWork,
+    FutureTimestamp,
+    StaleNonce,
+    InsufficientFunds,
+    WrongChain,
+    TooManyAncestors,
+    TooManyDescendants,
+}
+
+/* An address is a hex-encoded secp256k1 public key, compressed (33 bytes)
+   or uncompressed (65 bytes), that actually lies on the curve */
+pub fn is_valid_address(address: &str) -> bool {
+    match hex::decode(address) {
+        Ok(bytes) if bytes.len() == 33 || bytes.len() == 65 => PublicKey::from_str(address).is_ok(),
+        _ => false,
+    }
+}
+
+/* Load-testing helper: `count` small signed transfers from `wallet` to fresh random keys
+   on network `chain_id`, numbered from `first_nonce` */
+pub fn generate_test_transactions(
+    wallet: &KeyMaster,
+    chain_id: &str,
+    count: usize,
+    first_nonce: u64,
+    pow_difficulty: Option<u32>,
+) -> Result<Vec<Transaction>, KeyError> {
+    let mut rng = rand::thread_rng();
+    (first_nonce..first_nonce + count as u64)
+        .map(|nonce| {
+            let receiver = KeyMaster::new().public_key;
+            let amount = rng.gen_range(1..=100) as f64 / 100.0;
+            let mut tx = Transaction::new(wallet.public_key.clone(), receiver, amount, 0.0);
+            tx.nonce = nonce;
+            tx.chain_id = chain_id.to_string();
+            if let Some(difficulty) = pow_difficulty {
+                tx.solve_pow(difficulty);
+            }
+            tx.sign(wallet)?;
+            Ok(tx)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_transfer(key: &KeyMaster) -> Transaction {
+        let mut tx = Transaction::new(key.public_key.clone(), KeyMaster::new().public_key, 1.5, 0.1);
+        tx.nonce = 3;
+        tx.memo = "rent".to_string();
+        tx.sign(key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn public_keys_are_valid_addresses() {
+        let key = KeyMaster::new();
+        assert!(is_valid_address(&key.public_key));
+
+        let truncated = &key.public_key[..key.public_key.len() - 2];
+        for address in ["", "not hex", truncated, BURN_ADDRESS, COINBASE_SENDER] {
+            assert!(!is_valid_address(address), "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn created_at_survives_serialization_and_is_signed() {
+        let key = KeyMaster::new();
+        let tx = signed_transfer(&key);
+
+        let json = serde_json::to_string(&tx).unwrap();
+        let mut received: Transaction = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.created_at, tx.created_at);
+        assert!(received.verify());
+
+        received.created_at += 1;
+        assert!(!received.verify());
+    }
+
+    #[test]
+    fn signature_covers_every_transfer_field() {
+        let key = KeyMaster::new();
+        let tx = signed_transfer(&key);
+        assert!(tx.verify());
+
+        type Tamper = (&'static str, fn(&mut Transaction));
+        let tampers: [Tamper; 6] = [
+            ("receiver", |tx| tx.receiver = KeyMaster::new().public_key),
+            ("amount", |tx| tx.amount += 0.00000001),
+            ("fee", |tx| tx.fee = 0.0),
+            ("nonce", |tx| tx.nonce += 1),
+            ("memo", |tx| tx.memo.push('!')),
+            ("chain_id", |tx| tx.chain_id = "other".to_string()),
+        ];
+        for (field, tamper) in tampers {
+            let mut changed = tx.clone();
+            tamper(&mut changed);
+            assert!(!changed.verify(), "changing {} kept the signature valid", field);
+        }
+
+        // outside the signature by design
+        let mut solved = tx.clone();
+        solved.pow_nonce += 1;
+        assert!(solved.verify());
+    }
+}
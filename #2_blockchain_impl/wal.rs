@@ -0,0 +1,63 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::block::Block;
+
+/* Write-ahead log of accepted blocks: one JSON line per block, fsynced before
+   the block counts as accepted, replayed into the chain on startup */
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /* Opens (or creates) the log and cuts off a torn last line left by a crash,
+       so later appends start on a clean line */
+    pub fn open(path: &Path) -> io::Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let contents = fs::read(path)?;
+        let valid = valid_prefix_len(&contents);
+        if valid < contents.len() {
+            file.set_len(valid as u64)?;
+            file.sync_data()?;
+        }
+        Ok(Wal { path: path.to_path_buf(), file })
+    }
+
+    pub fn append(&mut self, blocks: &[Block]) -> io::Result<()> {
+        for block in blocks {
+            let mut line = serde_json::to_vec(block)?;
+            line.push(b'\n');
+            self.file.write_all(&line)?;
+        }
+        self.file.sync_data()
+    }
+
+    pub fn read_blocks(&self) -> io::Result<Vec<Block>> {
+        let contents = fs::read(&self.path)?;
+        let blocks = contents[..valid_prefix_len(&contents)]
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<Block>, _>>()?;
+        Ok(blocks)
+    }
+
+    /* Once the blocks are safely stored elsewhere the log can start over */
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+// bytes up to the end of the last complete line that holds a block
+fn valid_prefix_len(contents: &[u8]) -> usize {
+    let mut valid = 0;
+    for line in contents.split_inclusive(|byte| *byte == b'\n') {
+        if !line.ends_with(b"\n") || serde_json::from_slice::<Block>(line).is_err() {
+            break;
+        }
+        valid += line.len();
+    }
+    valid
+}